@@ -0,0 +1,261 @@
+use crate::mining;
+use crate::tx::{self, TxError, RPC_INVALID_ADDRESS_OR_KEY};
+use bitcoincore_rpc::bitcoin::amount::serde::as_btc;
+use bitcoincore_rpc::bitcoin::{Amount, Txid};
+use bitcoincore_rpc::json::CreateRawTransactionInput;
+use bitcoincore_rpc::{jsonrpc, RpcApi};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// A transaction and the child that pays for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bump {
+    pub parent: Txid,
+    pub child: Txid,
+}
+
+/// A replaceable transaction and the higher-fee one that replaced it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeeBump {
+    pub original_txid: Txid,
+    #[serde(with = "as_btc")]
+    pub original_fee: Amount,
+    pub txid: Txid,
+    #[serde(with = "as_btc")]
+    pub fee: Amount,
+}
+
+/// How many times the original fee rate a resent transaction pays.
+const RESEND_FEE_MULTIPLIER: f64 = 2.0;
+
+/// A stuck transaction the wallet abandoned and the one sent in its place.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Resend {
+    pub abandoned_txid: Txid,
+    /// Fee rate in sat/vB of the abandoned transaction, and of its
+    /// replacement.
+    pub abandoned_fee_rate: f64,
+    pub txid: Txid,
+    pub fee_rate: f64,
+}
+
+/// What `bumpfee` returns for a wallet that holds its private keys.
+#[derive(Deserialize)]
+struct BumpFeeResponse {
+    txid: Txid,
+    #[serde(with = "as_btc")]
+    origfee: Amount,
+    #[serde(with = "as_btc")]
+    fee: Amount,
+}
+
+/// Replace the unconfirmed, BIP125-replaceable `txid` with a copy paying a
+/// higher fee, using the `bumpfee` RPC.
+pub fn bump_fee(wallet: &impl RpcApi, txid: &Txid) -> bitcoincore_rpc::Result<FeeBump> {
+    let response: BumpFeeResponse = wallet.call("bumpfee", &[txid.to_string().into()])?;
+    debug!(
+        "replaced {txid} with {}, fee {} -> {} BTC",
+        response.txid,
+        response.origfee.to_btc(),
+        response.fee.to_btc()
+    );
+    Ok(FeeBump {
+        original_txid: *txid,
+        original_fee: response.origfee,
+        txid: response.txid,
+        fee: response.fee,
+    })
+}
+
+/// Abandon the stuck `txid` and send it again through `resend`, which is
+/// given a fee rate in sat/vB twice the original's.
+///
+/// The wallet only abandons a transaction that is neither confirmed nor in
+/// the mempool, so this is for one the node has since dropped (evicted, or
+/// lost in a restart); anything else fails with [`TxError::NotAbandonable`]
+/// before `resend` is called.
+pub fn abandon_and_resend(
+    wallet: &impl RpcApi,
+    txid: &Txid,
+    resend: impl FnOnce(f64) -> Result<Txid, TxError>,
+) -> Result<Resend, TxError> {
+    let tx = wallet.get_transaction(txid, None)?;
+    if tx.info.confirmations > 0 {
+        return Err(TxError::NotAbandonable(*txid));
+    }
+    let vsize = tx
+        .transaction()
+        .map_err(bitcoincore_rpc::Error::from)?
+        .vsize();
+    let abandoned_fee_rate = tx::fee_rate(tx::transaction_fee(wallet, &tx)?, vsize);
+
+    abandon(wallet, txid)?;
+    let fee_rate = abandoned_fee_rate * RESEND_FEE_MULTIPLIER;
+    let resent = resend(fee_rate)?;
+    debug!(
+        "abandoned {txid} at {abandoned_fee_rate} sat/vB, resent as {resent} at {fee_rate} sat/vB"
+    );
+    Ok(Resend {
+        abandoned_txid: *txid,
+        abandoned_fee_rate,
+        txid: resent,
+        fee_rate,
+    })
+}
+
+/// Mark `txid` abandoned in `wallet`, freeing the coins it spends, or fail
+/// with [`TxError::NotAbandonable`] if it's confirmed or in the mempool.
+pub fn abandon(wallet: &impl RpcApi, txid: &Txid) -> Result<(), TxError> {
+    let args = [txid.to_string().into()];
+    wallet
+        .call::<()>("abandontransaction", &args)
+        .map_err(|err| match err {
+            // The same code also covers a txid the wallet doesn't know
+            bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(ref rpc))
+                if rpc.code == RPC_INVALID_ADDRESS_OR_KEY && rpc.message.contains("abandon") =>
+            {
+                TxError::NotAbandonable(*txid)
+            }
+            err => TxError::Rpc(err),
+        })
+}
+
+/// Bump the unconfirmed `parent_txid` by child-pays-for-parent.
+///
+/// Spends the parent's change output back into `miner`, paying `extra_fee`,
+/// then mines a block so parent and child confirm together as a package.
+pub fn cpfp(miner: &impl RpcApi, parent_txid: &Txid, extra_fee: Amount) -> Result<Bump, TxError> {
+    // The parent's change is the Miner's only unconfirmed output from it
+    let mut change: Vec<_> = miner
+        .list_unspent(Some(0), Some(0), None, Some(true), None)?
+        .into_iter()
+        .filter(|utxo| utxo.txid == *parent_txid)
+        .collect();
+    let change = match change.len() {
+        0 => return Err(TxError::NoChangeToSpend(*parent_txid)),
+        1 => change.remove(0),
+        count => return Err(TxError::AmbiguousChange(count)),
+    };
+    let value = change
+        .amount
+        .checked_sub(extra_fee)
+        .filter(|value| *value > Amount::ZERO)
+        .ok_or(TxError::FeeExceedsChange {
+            change: change.amount,
+            fee: extra_fee,
+        })?;
+
+    // Send what's left of the change to a fresh Miner address
+    let address = miner.get_new_address(None, None)?.assume_checked();
+    let input = CreateRawTransactionInput {
+        txid: change.txid,
+        vout: change.vout,
+        sequence: None,
+    };
+    let outs = HashMap::from([(address.to_string(), value)]);
+    let unsigned = miner.create_raw_transaction(&[input], &outs, None, None)?;
+
+    let signed = miner.sign_raw_transaction_with_wallet(&unsigned, None, None)?;
+    if !signed.complete {
+        return Err(TxError::SigningIncomplete);
+    }
+    let child = miner.send_raw_transaction(&signed.hex)?;
+    debug!(
+        "child {child} spends {}:{} paying {} BTC extra fee",
+        change.txid,
+        change.vout,
+        extra_fee.to_btc()
+    );
+
+    // One block should take both, the child's fee making the parent worth mining
+    mining::generate_blocks(miner, 1, &address)?;
+    for txid in [parent_txid, &child] {
+        if miner.get_transaction(txid, None)?.info.confirmations < 1 {
+            return Err(TxError::Unconfirmed(*txid));
+        }
+    }
+    Ok(Bump {
+        parent: *parent_txid,
+        child,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockRpc;
+    use serde_json::json;
+
+    const PARENT: &str = "d39612cc243e8749268d667a76b5062bd10d7d60d612b50117274efe179699c4";
+
+    fn unspent(txid: &str, amount: f64) -> serde_json::Value {
+        json!({
+            "txid": txid,
+            "vout": 1,
+            "scriptPubKey": "",
+            "amount": amount,
+            "confirmations": 0,
+            "spendable": true,
+            "solvable": true,
+            "safe": true,
+        })
+    }
+
+    #[test]
+    fn bump_fee_reports_both_transactions() {
+        let replacement = "47142dca488cbdad513a88c44f2ca3722fd742f69d1d86b59bde1ad3d18556c5";
+        let wallet = MockRpc::new().on(
+            "bumpfee",
+            json!({"txid": replacement, "origfee": 0.00000141, "fee": 0.00000705, "errors": []}),
+        );
+        let bump = bump_fee(&wallet, &PARENT.parse().unwrap()).unwrap();
+        assert_eq!(bump.original_txid.to_string(), PARENT);
+        assert_eq!(bump.txid.to_string(), replacement);
+        assert_eq!(bump.original_fee, Amount::from_sat(141));
+        assert_eq!(bump.fee, Amount::from_sat(705));
+    }
+
+    #[test]
+    fn transaction_the_node_still_has_is_not_abandonable() {
+        let wallet = MockRpc::new().on_error(
+            "abandontransaction",
+            -5,
+            "Transaction not eligible for abandonment",
+        );
+        assert!(matches!(
+            abandon(&wallet, &PARENT.parse().unwrap()),
+            Err(TxError::NotAbandonable(_))
+        ));
+
+        let wallet = MockRpc::new().on_error(
+            "abandontransaction",
+            -5,
+            "Invalid or non-wallet transaction id",
+        );
+        assert!(matches!(
+            abandon(&wallet, &PARENT.parse().unwrap()),
+            Err(TxError::Rpc(_))
+        ));
+    }
+
+    #[test]
+    fn parent_without_unconfirmed_change_is_rejected() {
+        let other = "47142dca488cbdad513a88c44f2ca3722fd742f69d1d86b59bde1ad3d18556c5";
+        let miner = MockRpc::new().on("listunspent", json!([unspent(other, 1.0)]));
+        assert!(matches!(
+            cpfp(&miner, &PARENT.parse().unwrap(), Amount::from_sat(10_000)),
+            Err(TxError::NoChangeToSpend(_))
+        ));
+    }
+
+    #[test]
+    fn fee_larger_than_change_is_rejected() {
+        let miner = MockRpc::new().on("listunspent", json!([unspent(PARENT, 0.0001)]));
+        assert!(matches!(
+            cpfp(&miner, &PARENT.parse().unwrap(), Amount::from_sat(10_000)),
+            Err(TxError::FeeExceedsChange { .. })
+        ));
+        assert_eq!(miner.calls("sendrawtransaction"), 0);
+    }
+}
@@ -0,0 +1,271 @@
+use crate::mining::MiningError;
+use crate::tx::TxError;
+use crate::wallet::{InsufficientFunds, WalletError};
+use bitcoincore_rpc::bitcoin::{address, Amount, Network};
+use bitcoincore_rpc::jsonrpc;
+use bitcoincore_rpc::RpcApi;
+use std::io;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Exit code for a failure that fits no narrower category.
+pub const EXIT_FAILURE: i32 = 1;
+/// Exit code when the node can't be reached.
+pub const EXIT_CONNECTION: i32 = 2;
+/// Exit code when the Miner can't afford the payment.
+pub const EXIT_INSUFFICIENT_FUNDS: i32 = 3;
+/// Exit code for bad arguments or settings that don't match the node.
+pub const EXIT_CONFIG: i32 = 4;
+
+/// Bitcoin Core's `RPC_WALLET_INSUFFICIENT_FUNDS` error code.
+const RPC_WALLET_INSUFFICIENT_FUNDS: i32 = -6;
+
+/// Everything that can make a run fail.
+#[derive(Debug, Error)]
+pub enum AppError {
+    /// The node rejected or couldn't answer an RPC call.
+    #[error("RPC error: {0}")]
+    Rpc(#[from] bitcoincore_rpc::Error),
+    /// The payment transaction didn't look as expected.
+    #[error("{0}")]
+    Tx(#[from] TxError),
+    /// Blocks couldn't be mined for the Miner wallet.
+    #[error("{0}")]
+    Mining(#[from] MiningError),
+    /// The Miner wallet's encryption got in the way.
+    #[error("{0}")]
+    Wallet(#[from] WalletError),
+    /// The Miner wallet can't afford the payment.
+    #[error(
+        "insufficient funds: the wallet has {} BTC but {} BTC is needed",
+        .have.to_btc(),
+        .need.to_btc()
+    )]
+    InsufficientFunds { have: Amount, need: Amount },
+    /// The coins under `--from-label` can't afford the payment.
+    #[error(
+        "insufficient funds under label {label:?}: its coins hold {} BTC but {} BTC is needed",
+        .have.to_btc(),
+        .need.to_btc()
+    )]
+    LabelFunds {
+        label: String,
+        have: Amount,
+        need: Amount,
+    },
+    /// The node handed out an address for a different network.
+    #[error("unexpected address: {0}")]
+    Address(#[from] address::Error),
+    /// The node doesn't accept an address given on the command line.
+    #[error("the node rejects address {address}: {reason}")]
+    RejectedAddress { address: String, reason: String },
+    /// The node runs a different chain than the one selected.
+    #[error("the node runs {actual}, not {expected}: check BITCOIN_RPC_URL and --network")]
+    WrongChain { expected: Network, actual: Network },
+    /// The node runs mainnet and `--i-know-this-is-mainnet` wasn't given.
+    #[error("the node runs mainnet: pass --i-know-this-is-mainnet to use it anyway")]
+    MainnetRefused,
+    /// The run took longer than `--deadline-secs` allowed.
+    #[error("gave up after the {}s deadline", .0.as_secs())]
+    DeadlineExceeded(Duration),
+    /// Writing the report failed.
+    #[error("{0}")]
+    Io(#[from] io::Error),
+}
+
+impl AppError {
+    /// Replace the node's "insufficient funds" error from a failed send with
+    /// [`AppError::InsufficientFunds`], looking up what `wallet` actually has.
+    /// Any other error is returned unchanged.
+    pub(crate) fn explain_insufficient_funds(self, wallet: &impl RpcApi, need: Amount) -> Self {
+        let rpc = match &self {
+            AppError::Rpc(err) | AppError::Tx(TxError::Rpc(err)) => err,
+            _ => return self,
+        };
+        let insufficient = matches!(
+            rpc,
+            bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(err))
+                if err.code == RPC_WALLET_INSUFFICIENT_FUNDS
+        );
+        if !insufficient {
+            return self;
+        }
+        match wallet.get_balance(None, None) {
+            Ok(have) => AppError::InsufficientFunds { have, need },
+            Err(_) => self,
+        }
+    }
+}
+
+/// The process exit code for `err`, so callers can tell failures apart:
+///
+/// - [`EXIT_CONNECTION`] (2): the node couldn't be reached at all.
+/// - [`EXIT_INSUFFICIENT_FUNDS`] (3): the Miner can't afford the payment,
+///   including on signet before it's been funded externally.
+/// - [`EXIT_CONFIG`] (4): the network, addresses or arguments don't fit the
+///   node, e.g. the wrong chain, mining on mainnet or a wrong passphrase.
+/// - [`EXIT_FAILURE`] (1): anything else.
+pub fn exit_code(err: &AppError) -> i32 {
+    match err {
+        AppError::Rpc(rpc)
+        | AppError::Tx(TxError::Rpc(rpc))
+        | AppError::Mining(MiningError::Rpc(rpc))
+        | AppError::Wallet(WalletError::Rpc(rpc))
+        | AppError::Wallet(WalletError::Setup { err: rpc, .. })
+        | AppError::Tx(TxError::Mining(MiningError::Rpc(rpc)))
+            if is_connection_error(rpc) =>
+        {
+            EXIT_CONNECTION
+        }
+        AppError::Wallet(WalletError::Setup { .. }) => EXIT_FAILURE,
+        AppError::InsufficientFunds { .. }
+        | AppError::Mining(MiningError::FundExternally { .. })
+        | AppError::LabelFunds { .. }
+        | AppError::Mining(MiningError::FundsTimeout { .. }) => EXIT_INSUFFICIENT_FUNDS,
+        AppError::Address(_)
+        | AppError::RejectedAddress { .. }
+        | AppError::Wallet(
+            WalletError::Locked(_)
+            | WalletError::WrongPassphrase(_)
+            | WalletError::NotEncrypted(_)
+            | WalletError::InvalidDescriptor(_),
+        )
+        | AppError::WrongChain { .. }
+        | AppError::MainnetRefused
+        | AppError::Mining(MiningError::Disabled(_))
+        | AppError::Tx(TxError::Mining(MiningError::Disabled(_))) => EXIT_CONFIG,
+        _ => EXIT_FAILURE,
+    }
+}
+
+/// Whether `err` means the request never got an answer from the node.
+fn is_connection_error(err: &bitcoincore_rpc::Error) -> bool {
+    matches!(
+        err,
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Transport(_))
+    )
+}
+
+impl From<InsufficientFunds> for AppError {
+    fn from(err: InsufficientFunds) -> Self {
+        AppError::InsufficientFunds {
+            have: err.balance,
+            need: err.needed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockRpc;
+    use serde_json::json;
+
+    fn rpc_error(code: i32, message: &str) -> AppError {
+        AppError::Rpc(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(
+            jsonrpc::error::RpcError {
+                code,
+                message: message.to_owned(),
+                data: None,
+            },
+        )))
+    }
+
+    fn connection_refused() -> bitcoincore_rpc::Error {
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Transport(Box::new(io::Error::from(
+            io::ErrorKind::ConnectionRefused,
+        ))))
+    }
+
+    #[test]
+    fn exit_codes_tell_error_categories_apart() {
+        assert_eq!(exit_code(&AppError::Rpc(connection_refused())), 2);
+        assert_eq!(exit_code(&TxError::Rpc(connection_refused()).into()), 2);
+        assert_eq!(exit_code(&MiningError::Rpc(connection_refused()).into()), 2);
+        let setup = WalletError::Setup {
+            name: "Trader".to_owned(),
+            err: connection_refused(),
+        };
+        assert_eq!(exit_code(&setup.into()), 2);
+        assert_eq!(exit_code(&rpc_error(-26, "min relay fee not met")), 1);
+
+        let funds = AppError::InsufficientFunds {
+            have: Amount::ZERO,
+            need: Amount::ONE_BTC,
+        };
+        assert_eq!(exit_code(&funds), 3);
+        let unfunded = MiningError::FundExternally {
+            network: Network::Signet,
+            address: "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx"
+                .parse::<bitcoincore_rpc::bitcoin::Address<_>>()
+                .unwrap()
+                .assume_checked(),
+            have: Amount::ZERO,
+            need: Amount::ONE_BTC,
+        };
+        assert_eq!(exit_code(&unfunded.into()), 3);
+        let label = AppError::LabelFunds {
+            label: "Savings".to_owned(),
+            have: Amount::ZERO,
+            need: Amount::ONE_BTC,
+        };
+        assert_eq!(exit_code(&label), 3);
+
+        let wrong_chain = AppError::WrongChain {
+            expected: Network::Regtest,
+            actual: Network::Testnet,
+        };
+        assert_eq!(exit_code(&wrong_chain), 4);
+        assert_eq!(exit_code(&AppError::MainnetRefused), 4);
+        let rejected = AppError::RejectedAddress {
+            address: "bcrt1q".to_owned(),
+            reason: "Invalid Bech32 checksum".to_owned(),
+        };
+        assert_eq!(exit_code(&rejected), 4);
+        assert_eq!(
+            exit_code(&WalletError::WrongPassphrase("Miner".to_owned()).into()),
+            4
+        );
+        assert_eq!(exit_code(&address::Error::UnrecognizedScript.into()), 4);
+        assert_eq!(
+            exit_code(&MiningError::Disabled(Network::Bitcoin).into()),
+            4
+        );
+
+        assert_eq!(exit_code(&TxError::TraderNotPaid.into()), 1);
+        assert_eq!(
+            exit_code(&MiningError::NotSpendable { blocks: 101 }.into()),
+            1
+        );
+        assert_eq!(exit_code(&io::Error::other("disk full").into()), 1);
+        let wallet_rpc = WalletError::Rpc(bitcoincore_rpc::Error::ReturnedError(
+            "Wallet file not found".to_owned(),
+        ));
+        assert_eq!(exit_code(&wallet_rpc.into()), 1);
+    }
+
+    #[test]
+    fn insufficient_funds_rpc_error_reports_both_amounts() {
+        let wallet = MockRpc::new().on("getbalance", json!(12.5));
+        let need = Amount::from_sat(2_000_000_000);
+
+        let err = rpc_error(-6, "Insufficient funds").explain_insufficient_funds(&wallet, need);
+
+        assert!(matches!(
+            err,
+            AppError::InsufficientFunds { have, need: n }
+                if have == Amount::from_sat(1_250_000_000) && n == need
+        ));
+        let msg = err.to_string();
+        assert!(msg.contains("12.5 BTC") && msg.contains("20 BTC"));
+    }
+
+    #[test]
+    fn other_rpc_errors_are_left_alone() {
+        let wallet = MockRpc::new();
+        let err = rpc_error(-26, "min relay fee not met")
+            .explain_insufficient_funds(&wallet, Amount::from_sat(1));
+        assert!(matches!(err, AppError::Rpc(_)));
+        assert_eq!(wallet.calls("getbalance"), 0);
+    }
+}
@@ -0,0 +1,1281 @@
+//! Drives a Miner to Trader payment on a Bitcoin Core node and reports on it.
+
+use bitcoincore_rpc::bitcoin::{Address, Amount, BlockHash, OutPoint, Transaction, TxOut, Txid};
+use bitcoincore_rpc::json::AddressType;
+use bitcoincore_rpc::{Client, RpcApi};
+use log::{debug, info, warn};
+use serde_json::json;
+use std::cell::RefCell;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+pub mod bump;
+pub mod cli;
+pub mod error;
+pub mod mining;
+#[cfg(test)]
+mod mock;
+pub mod report;
+pub mod rpc;
+pub mod send;
+pub mod settings;
+pub mod state;
+pub mod tx;
+pub mod wallet;
+
+pub use error::AppError;
+pub use report::TxReport;
+
+/// Everything a run needs: where the node is and what to do on it.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub rpc: rpc::RpcConfig,
+    pub options: cli::Args,
+}
+
+/// Connections to the node and to both wallets.
+pub struct Session {
+    pub node: Client,
+    pub miner: Client,
+    pub trader: Client,
+    /// Type of the addresses to hand out, once checked against the node.
+    pub address_type: Option<AddressType>,
+    /// Wallets this session created and unloads when dropped.
+    created_wallets: Vec<String>,
+    /// For opening further connections to the node.
+    rpc_config: rpc::RpcConfig,
+    /// Connections to further wallets, opened as they're needed.
+    wallet_clients: RefCell<rpc::WalletClients>,
+}
+
+impl Session {
+    /// Connect to the node and make both wallets available, creating them if
+    /// they don't exist yet.
+    pub fn open(config: &Config) -> Result<Session, AppError> {
+        let args = &config.options;
+
+        // Connect to RPC (base connection without wallet), giving a node that
+        // was just started a few seconds to come up
+        debug!("connecting to {}", config.rpc.url);
+        let node = rpc::connect_with_retry(&config.rpc, 5, Duration::from_millis(500))?;
+        rpc::preflight(&node, args.network, args.allow_mainnet)?;
+        for address in args.external_addresses() {
+            rpc::validate_address(&node, address)?;
+        }
+
+        // Create wallet-specific clients
+        let names = &args.wallets;
+        let miner = rpc::connect(&config.rpc, Some(&names.miner))?;
+        let trader = rpc::connect(&config.rpc, Some(&names.trader))?;
+
+        // Pick the address type, falling back if the node is too old for taproot
+        let address_type = match args.address_type {
+            Some(requested) => Some(wallet::supported_address_type(
+                requested,
+                node.get_network_info()?.version,
+            )),
+            None => None,
+        };
+
+        let mut session = Session {
+            node,
+            miner,
+            trader,
+            address_type,
+            created_wallets: Vec::new(),
+            rpc_config: config.rpc.clone(),
+            wallet_clients: RefCell::new(rpc::WalletClients::new(&config.rpc)),
+        };
+
+        // Load both wallets at once, creating them if they don't exist yet.
+        // With --cleanup the ones created here are unloaded again when the
+        // session is dropped, even if the other one failed
+        let wallets = [
+            (names.miner.as_str(), args.miner_passphrase.as_deref()),
+            (names.trader.as_str(), None),
+        ];
+        let setups = wallet::ensure_wallets(
+            &session.node,
+            &wallets,
+            !args.legacy_wallets,
+            !args.sequential_wallets,
+        );
+        let mut failed = None;
+        for ((name, _), setup) in wallets.iter().zip(setups) {
+            match setup {
+                Ok(wallet::WalletSetup::Created) if args.cleanup => {
+                    session.created_wallets.push(name.to_string());
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    failed.get_or_insert(err);
+                }
+            }
+        }
+        match failed {
+            Some(err) => Err(err.into()),
+            None => Ok(session),
+        }
+    }
+
+    /// The address to mine to: `--mine-to` if given, else the Miner's
+    /// "Mining Reward" address, created on first use.
+    fn mining_address(&self, args: &cli::Args) -> Result<Address, AppError> {
+        if !args.allow_mainnet {
+            mining::check_network(args.network)?;
+        }
+        Ok(match &args.mine_to {
+            Some(address) => address.clone(),
+            None => wallet::get_or_create_labeled_address(
+                &self.miner,
+                "Mining Reward",
+                self.address_type,
+            )?
+            .require_network(args.network)?,
+        })
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        for name in &self.created_wallets {
+            match self.node.unload_wallet(Some(name)) {
+                Ok(_) => info!("Unloaded wallet {name}"),
+                Err(err) => warn!("could not unload wallet {name}: {err}"),
+            }
+        }
+    }
+}
+
+/// Set up both wallets, fund the Miner, pay the Trader, confirm the payment
+/// and return its report.
+///
+/// With `options.dry_run` set, the payment is only funded, never broadcast,
+/// and the returned report has no block details.
+pub fn run(config: &Config) -> Result<TxReport, AppError> {
+    let args = &config.options;
+    let session = Session::open(config)?;
+    let mining_address = session.mining_address(args)?;
+
+    // A dry run never mines, so it can only preview with funds already there
+    if !args.dry_run {
+        fund(&session, args, &mining_address)?;
+    }
+    pay_from(&session, args, &mining_address)
+}
+
+/// Make both wallets available and mine until the Miner can afford the
+/// payment. Returns the number of blocks mined.
+pub fn setup(config: &Config) -> Result<u64, AppError> {
+    let session = Session::open(config)?;
+    let mining_address = session.mining_address(&config.options)?;
+    fund(&session, &config.options, &mining_address)
+}
+
+/// Pay the Trader from an already funded Miner, confirm the payment and
+/// return its report.
+pub fn pay(config: &Config) -> Result<TxReport, AppError> {
+    let session = Session::open(config)?;
+    let mining_address = session.mining_address(&config.options)?;
+    pay_from(&session, &config.options, &mining_address)
+}
+
+/// The payment recorded in the `--once` state file at `path`, if the Miner
+/// wallet still has it and it's confirmed. A run that finds one has nothing
+/// left to do.
+pub fn completed_payment(config: &Config, path: &Path) -> Result<Option<Txid>, AppError> {
+    let Some(txid) = state::load_state(path)? else {
+        return Ok(None);
+    };
+    let session = Session::open(config)?;
+    Ok(tx::is_confirmed(&session.miner, &txid)?.then_some(txid))
+}
+
+/// Run `work` to completion, or fail with [`AppError::DeadlineExceeded`] as
+/// soon as it has taken longer than `deadline`.
+///
+/// With a deadline `work` runs on a thread of its own, which is left behind
+/// if it overruns: a blocked RPC call can't be interrupted, so the caller is
+/// expected to exit rather than wait for it. A panic in `work` is passed on.
+pub fn with_deadline<T: Send + 'static>(
+    deadline: Option<Duration>,
+    work: impl FnOnce() -> Result<T, AppError> + Send + 'static,
+) -> Result<T, AppError> {
+    let Some(deadline) = deadline else {
+        return work();
+    };
+    let (done, finished) = mpsc::channel();
+    let worker = thread::spawn(move || {
+        // The receiver is gone if the deadline already passed
+        let _ = done.send(work());
+    });
+    match finished.recv_timeout(deadline) {
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(AppError::DeadlineExceeded(deadline)),
+        Err(mpsc::RecvTimeoutError::Disconnected) => match worker.join() {
+            Err(panic) => std::panic::resume_unwind(panic),
+            Ok(()) => unreachable!("the worker sends its result before finishing"),
+        },
+    }
+}
+
+/// Rebuild the report of the confirmed payment `txid` from what the node and
+/// both wallets know about it.
+///
+/// The Trader's output is the one its wallet owns, change the one the
+/// Miner's owns, and the Miner address is the one the first input spent from.
+///
+/// A transaction the Miner wallet doesn't know gets a partial report from
+/// the chain instead, found in `block_hash` if given (needed on nodes
+/// without `-txindex`).
+pub fn report(
+    config: &Config,
+    txid: &Txid,
+    block_hash: Option<&BlockHash>,
+) -> Result<TxReport, AppError> {
+    let args = &config.options;
+    let session = Session::open(config)?;
+    let miner = prefetch_report(&session.miner, txid, args.batch)?;
+    let report = rebuild_report(
+        &session.node,
+        &miner,
+        &session.trader,
+        txid,
+        block_hash,
+        args,
+    )?;
+    log_batching(&miner);
+    Ok(report)
+}
+
+/// [`report`] on whichever clients it's given.
+fn rebuild_report(
+    node: &impl RpcApi,
+    miner: &(impl RpcApi + Sync),
+    trader: &(impl RpcApi + Sync),
+    txid: &Txid,
+    block_hash: Option<&BlockHash>,
+    args: &cli::Args,
+) -> Result<TxReport, AppError> {
+    let tx = match tx::wallet_transaction(miner, txid) {
+        Ok(tx) => tx,
+        Err(tx::TxError::NotInWallet(_)) => {
+            info!("{txid} isn't a Miner wallet transaction, reporting what the chain shows");
+            return external_report(node, txid, block_hash, args);
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let (block_height, block_hash) = tx::confirming_block(node, &tx)?;
+    let decoded = miner.get_raw_transaction(txid, None)?;
+
+    // The Trader first, then every other payee that isn't change
+    let (trader_address, _) = tx::find_trader_output(trader, &decoded, args.network)?;
+    // The Trader may have been paid in several outputs
+    let sent = tx::total_to_address(&decoded, &trader_address);
+    let change = tx::find_change_output(
+        miner,
+        &decoded,
+        std::slice::from_ref(&trader_address),
+        args.network,
+    )?;
+    let mut payments = vec![(trader_address.clone(), sent)];
+    for output in &decoded.output {
+        // Data carriers pay nobody
+        let Some(address) = tx::output_address(output, args.network)? else {
+            continue;
+        };
+        let is_change = change
+            .as_ref()
+            .is_some_and(|(_, change)| *change == address);
+        if address != trader_address && !is_change {
+            payments.push((address, output.value));
+        }
+    }
+
+    let miner_address = tx::input_address(miner, &decoded, args.network)?;
+    let inputs = tx::total_input_amount(miner, &decoded)?;
+    let fee = tx::transaction_fee(miner, &tx)?;
+
+    // Every satoshi spent must show up as a payment, change or the fee
+    let outputs: Vec<Amount> = payments
+        .iter()
+        .map(|(_, amount)| *amount)
+        .chain(change.iter().map(|(output, _)| output.value))
+        .collect();
+    tx::verify_balance_equation(inputs, &outputs, fee)?;
+
+    let mut report = report::build_report(
+        &tx,
+        &miner_address,
+        inputs,
+        fee,
+        &payments,
+        change.as_ref(),
+        block_height,
+    )?;
+    report.replaceable = decoded.is_explicitly_rbf();
+    report.set_metrics(tx::tx_metrics(&decoded));
+    report.set_output_types(&decoded);
+    report.set_op_return(&decoded);
+    log_change(&report);
+    if args.list_inputs {
+        report.inputs = tx::describe_inputs(miner, &decoded, args.network)?;
+    }
+    if args.include_raw {
+        report.set_raw_hex(&decoded)?;
+    }
+    if args.block_details {
+        report.block_details = Some(tx::block_details(node, &block_hash)?);
+    }
+    Ok(report)
+}
+
+/// The partial report of `txid`, a transaction the Miner wallet doesn't
+/// know, from what the node alone has on it: no fee, Miner, Trader or change.
+fn external_report(
+    node: &impl RpcApi,
+    txid: &Txid,
+    block_hash: Option<&BlockHash>,
+    args: &cli::Args,
+) -> Result<TxReport, AppError> {
+    let info = tx::chain_transaction(node, txid, block_hash)?;
+    let decoded = info.transaction().map_err(bitcoincore_rpc::Error::from)?;
+    let block = match info.blockhash {
+        Some(hash) => Some((node.get_block_header_info(&hash)?.height as u64, hash)),
+        None => None,
+    };
+    let confirmations = info.confirmations.unwrap_or(0);
+
+    let mut report = report::build_external(&decoded, args.network, block, confirmations)?;
+    report.replaceable = decoded.is_explicitly_rbf();
+    report.set_metrics(tx::tx_metrics(&decoded));
+    report.set_output_types(&decoded);
+    report.set_op_return(&decoded);
+    if args.include_raw {
+        report.set_raw_hex(&decoded)?;
+    }
+    if let Some((_, hash)) = block.filter(|_| args.block_details) {
+        report.block_details = Some(tx::block_details(node, &hash)?);
+    }
+    Ok(report)
+}
+
+/// Wrap `miner` for reading the confirmed transaction `txid` back. With
+/// `batch` set, everything the report reads from the Miner wallet is fetched
+/// up front in two batched requests: the transaction itself, then every
+/// transaction it spends from.
+fn prefetch_report<'a, C: rpc::BatchRpc>(
+    miner: &'a C,
+    txid: &Txid,
+    batch: bool,
+) -> Result<rpc::Prefetched<'a, C>, AppError> {
+    let prefetched = rpc::Prefetched::new(miner);
+    if !batch {
+        return Ok(prefetched);
+    }
+
+    // The same arguments get_transaction and get_raw_transaction send
+    let raw = |txid: &Txid| ("getrawtransaction", vec![json!(txid), json!(false)]);
+    // A transaction the wallet doesn't know is reported from the chain,
+    // with nothing to batch
+    let first = prefetched.prefetch(&[("gettransaction", vec![json!(txid)]), raw(txid)]);
+    match first.map_err(|err| tx::not_in_wallet(err, txid)) {
+        Ok(()) => {}
+        Err(tx::TxError::NotInWallet(_)) => return Ok(prefetched),
+        Err(err) => return Err(err.into()),
+    }
+    let decoded = prefetched.get_raw_transaction(txid, None)?;
+    let mut parents: Vec<Txid> = decoded
+        .input
+        .iter()
+        .filter(|input| !input.previous_output.is_null())
+        .map(|input| input.previous_output.txid)
+        .collect();
+    parents.sort();
+    parents.dedup();
+    prefetched.prefetch(&parents.iter().map(raw).collect::<Vec<_>>())?;
+    Ok(prefetched)
+}
+
+/// Log how much batching saved `miner` while reporting.
+fn log_batching(miner: &rpc::Prefetched<'_, impl rpc::BatchRpc>) {
+    let stats = miner.stats();
+    if stats.requests > 0 {
+        info!(
+            "Batched {} report calls into {} requests ({:?}), saving {} round trips (about {:?})",
+            stats.hits,
+            stats.requests,
+            stats.elapsed,
+            stats.round_trips_saved(),
+            stats.time_saved()
+        );
+    }
+}
+
+/// Mine to `mining_address` until the Miner can pay `args.total()` plus the
+/// fee buffer. Returns the number of blocks mined.
+///
+/// Where blocks can't be mined locally (signet) nothing is mined, and the
+/// Miner must already hold enough, sent to `mining_address` from elsewhere.
+/// With `--no-mine` it's given until the confirmation timeout to get there.
+/// With `--reset-chain` the regtest chain is rewound to genesis first.
+fn fund(session: &Session, args: &cli::Args, mining_address: &Address) -> Result<u64, AppError> {
+    let needed = args.total() + wallet::FEE_BUFFER;
+    if args.no_mine {
+        let have = mining::wait_for_funds(&session.miner, needed, args.confirm_timeout)?;
+        info!("Miner holds {} BTC, mined elsewhere", have.to_btc());
+        return Ok(0);
+    }
+    if !mining::mines_locally(args.network) {
+        mining::require_external_funds(&session.miner, mining_address, needed, args.network)?;
+        return Ok(0);
+    }
+    if args.reset_chain {
+        match mining::reset_chain(&session.node)? {
+            0 => info!("Chain is already at genesis"),
+            rewound => info!("Rewound the chain by {rewound} blocks to genesis"),
+        }
+    }
+
+    // Coinbase outputs can't be spent until 100 blocks have been built on top
+    // of them, so a fresh chain needs 101 blocks before the balance shows up.
+    // Wallets funded by an earlier run skip this step.
+    let mined = mining::ensure_spendable(
+        &session.miner,
+        &session.node,
+        mining_address,
+        needed,
+        args.maturity_blocks,
+    )?;
+
+    // Block 1's reward is the first to mature; what it paid depends on the
+    // chain's halving schedule, so it's read rather than assumed
+    let first_block = session.node.get_block_hash(1)?;
+    let reward = mining::coinbase_reward(&session.node, &first_block)?;
+    info!("Block 1's coinbase paid {} BTC", reward.to_btc());
+
+    // On a fresh chain every matured coinbase went to the Miner, so its
+    // balance should be exactly what those blocks paid. Anything else means
+    // the chain or wallet wasn't empty to begin with, which is allowed.
+    if mined > 0 && args.mine_to.is_none() {
+        let expected = mining::expected_matured_balance(mined, reward);
+        let balance = session.miner.get_balance(None, None)?;
+        if balance != expected {
+            warn!(
+                "Miner holds {} BTC after mining {mined} blocks, expected {} BTC on a fresh chain; \
+                 the chain or wallet wasn't empty at the start",
+                balance.to_btc(),
+                expected.to_btc()
+            );
+        }
+    }
+    Ok(mined)
+}
+
+/// Pay the Trader (and any extra recipients) from the Miner in one
+/// transaction, mine it to `mining_address` and report on it.
+fn pay_from(
+    session: &Session,
+    args: &cli::Args,
+    mining_address: &Address,
+) -> Result<TxReport, AppError> {
+    let (miner, trader) = (&session.miner, &session.trader);
+    let total = args.total();
+
+    // Get miner balance
+    let miner_balance = miner.get_balance(None, None)?;
+    info!("Miner balance: {} BTC", miner_balance.to_btc());
+
+    // Generate trader address, unless paying an external one or one derived
+    // from a descriptor
+    let trader_address = match &args.trader_address {
+        Some(address) => {
+            if args.watch_trader {
+                debug!("importing {address} into the Trader wallet as watch-only");
+                trader.import_address(address, Some("Received"), Some(false))?;
+            }
+            address.clone()
+        }
+        None => match &args.trader_descriptor {
+            Some(descriptor) => {
+                let address = wallet::import_descriptor_address(trader, descriptor)?
+                    .require_network(args.network)?;
+                info!("Paying {address}, derived from the imported descriptor");
+                address
+            }
+            None => trader
+                .get_new_address(Some("Received"), session.address_type)?
+                .require_network(args.network)?,
+        },
+    };
+    // Pay the Trader first, then any extra recipients in the same transaction
+    let mut payments = vec![(trader_address.clone(), args.amount)];
+    payments.extend(args.recipients.iter().cloned());
+
+    // Make sure the Miner can afford the payment before asking the node to
+    // send it, from the picked or labeled coins alone if there are any
+    let spend_utxos = match &args.from_label {
+        Some(label) => label_inputs(miner, label, total)?,
+        None => args.spend_utxos.clone(),
+    };
+    if spend_utxos.is_empty() {
+        wallet::check_funds(miner_balance, total)?;
+    } else {
+        let selected = send::selected_utxos_value(miner, &spend_utxos)?;
+        wallet::check_funds(selected, total)?;
+    }
+
+    // Change may only go back to the Miner, or it would be lost as a payment
+    if let Some(change_address) = &args.change_address {
+        tx::assert_is_mine(miner, change_address)?;
+    }
+
+    // Show which coins the wallet has to choose from, and the keys behind them
+    if args.verbose {
+        wallet::dump_utxos(miner)?;
+        if !args.legacy_wallets {
+            for descriptor in wallet::wallet_descriptors(miner)? {
+                info!("Miner descriptor: {descriptor}");
+            }
+        }
+    }
+
+    // Send the requested amount (20 BTC by default) from Miner to Trader,
+    // batching any extra recipients into the same transaction
+    let options = send::SendOptions {
+        fee_rate: args.fee_rate,
+        replaceable: args.replaceable,
+        change_address: args.change_address.clone(),
+        inputs: spend_utxos,
+        op_return: args.op_return.clone(),
+        comment: args.comment.clone(),
+    };
+
+    // Don't overpay if fees have spiked
+    send::check_fee_rate(miner, &options, args.max_fee_rate)?;
+
+    // For a dry run, fund the transaction without signing it and report what
+    // it would look like instead of sending and mining
+    if args.dry_run {
+        let (funded, fee) = send::preview_payments(miner, &payments, &options)?;
+        let change = change_output(miner, &funded, &payments, args)?;
+        let inputs = tx::total_input_amount(miner, &funded)?;
+        let mut preview = report::build_preview(
+            &funded,
+            mining_address,
+            inputs,
+            fee,
+            &payments,
+            change.as_ref(),
+        );
+        preview.replaceable = funded.is_explicitly_rbf();
+        preview.set_metrics(tx::tx_metrics(&funded));
+        preview.set_output_types(&funded);
+        preview.set_op_return(&funded);
+        preview.funding_label = args.from_label.clone();
+        log_change(&preview);
+        if args.list_inputs {
+            preview.inputs = tx::describe_inputs(miner, &funded, args.network)?;
+        }
+        if args.include_raw {
+            preview.set_raw_hex(&funded)?;
+        }
+        return Ok(preview);
+    }
+
+    // Remember the tip, to list everything the Miner saw from here on
+    let tip_before_send = miner.get_best_block_hash()?;
+
+    // An encrypted Miner is unlocked only while sending and bumping
+    let unlocked = match &args.miner_passphrase {
+        Some(passphrase) => Some(wallet::unlock(miner, &args.wallets.miner, passphrase)?),
+        None => {
+            wallet::check_unlocked(miner, &args.wallets.miner)?;
+            None
+        }
+    };
+
+    // Split the balance into many small coins first, so the payment has
+    // plenty to choose from
+    let fan_out = match args.fan_out {
+        Some(outputs) => Some(fan_out(session, args, mining_address, outputs)?),
+        None => None,
+    };
+    let send = |options: &send::SendOptions| {
+        send::send_with_retry(send::SEND_ATTEMPTS, send::SEND_RETRY_DELAY, || {
+            if args.psbt {
+                send::send_payments_psbt(miner, &payments, options)
+                    .map(|(txid, stages)| (txid, Some(stages)))
+            } else {
+                send::send_payments(miner, &payments, options).map(|txid| (txid, None))
+            }
+        })
+    };
+    // A payment an interrupted run left in the mempool is picked up rather
+    // than made again, and a new one is recorded as soon as it's broadcast
+    let saved = match &args.once {
+        Some(path) => state::load_state(path)?,
+        None => None,
+    };
+    let sent = send::resume_or_send(miner, saved.as_ref(), || send(&options));
+    let sent = sent.map_err(AppError::from);
+    let (mut txid, mut psbt) = sent.map_err(|err| err.explain_insufficient_funds(miner, total))?;
+    let record = |txid: &Txid| match &args.once {
+        Some(path) => state::save_state(path, txid),
+        None => Ok(()),
+    };
+    record(&txid)?;
+
+    // Optionally give up on it and send it again at a higher fee; the new
+    // transaction is the one that gets mined and reported on
+    let resend = if args.abandon_resend {
+        let resend = bump::abandon_and_resend(miner, &txid, |fee_rate| {
+            let options = send::SendOptions {
+                fee_rate: Some(fee_rate),
+                ..options.clone()
+            };
+            let (txid, stages) = send(&options)?;
+            psbt = stages;
+            Ok(txid)
+        })?;
+        info!(
+            "Abandoned {} and resent it as {}",
+            resend.abandoned_txid, resend.txid
+        );
+        txid = resend.txid;
+        Some(resend)
+    } else {
+        None
+    };
+
+    // Optionally replace it with a higher-fee copy; the replacement is the one
+    // that gets mined and reported on
+    let fee_bump = if args.bump_fee {
+        let bump = bump::bump_fee(miner, &txid)?;
+        info!("Replaced {} with {}", bump.original_txid, bump.txid);
+        txid = bump.txid;
+        Some(bump)
+    } else {
+        None
+    };
+    drop(unlocked);
+    if resend.is_some() || fee_bump.is_some() {
+        record(&txid)?;
+    }
+
+    // Make sure the node actually took the transaction before mining for it
+    let mempool = tx::check_mempool(miner, &txid)?;
+    info!(
+        "Transaction {txid} in mempool: {} BTC fee, {} ancestors, {} descendants",
+        mempool.fee.to_btc(),
+        mempool.ancestor_count,
+        mempool.descendant_count
+    );
+
+    // Mine until the transaction is buried deep enough (one block, normally),
+    // or leave that to a background generator standing in for a live chain
+    let generator = match args.mine_interval {
+        Some(interval) => Some(mining::start_block_generator(
+            rpc::connect(&session.rpc_config, None)?,
+            mining_address.clone(),
+            interval,
+        )),
+        None => None,
+    };
+    // A coinbase message needs a block built by hand; any further
+    // confirmations are mined as usual
+    if let Some(message) = args
+        .coinbase_message
+        .as_deref()
+        .filter(|_| generator.is_none())
+    {
+        let hash =
+            mining::mine_block_with_message(&session.node, mining_address, message.as_bytes())?;
+        info!("Mined block {hash} with coinbase message {message:?}");
+    }
+    mining::wait_for_confirmation(
+        miner,
+        &txid,
+        args.confirmations,
+        (generator.is_none() && args.mines_locally()).then_some(mining_address),
+        args.confirm_timeout,
+    )?;
+    if let Some(generator) = generator {
+        let mined = generator.stop()?;
+        info!("Background generator mined {mined} blocks");
+    }
+
+    // Show what else touched the Miner wallet meanwhile, for context
+    for summary in wallet::list_since(miner, &tip_before_send)? {
+        info!(
+            "Since the send: {} {:?} {} BTC, {} confirmations",
+            summary.txid,
+            summary.category,
+            summary.amount.to_btc(),
+            summary.confirmations
+        );
+    }
+
+    // Get transaction details, including the block that confirmed it; with
+    // more than one confirmation that's no longer the chain tip
+    let miner = &prefetch_report(miner, &txid, args.batch)?;
+    let tx = miner.get_transaction(&txid, None)?;
+    let (block_height, block_hash) = tx::confirming_block(&session.node, &tx)?;
+    let decoded = miner.get_raw_transaction(&txid, None)?;
+
+    // Double-check every payee got what was asked for before reporting
+    for (address, amount) in &payments {
+        tx::verify_payment(&decoded, address, *amount)?;
+    }
+
+    // Find change output (the one paying back into the Miner wallet), if the
+    // wallet added one
+    let change = change_output(miner, &decoded, &payments, args)?;
+
+    // Add up what the spent outputs were worth (a 50 BTC coinbase, normally)
+    let inputs = tx::total_input_amount(miner, &decoded)?;
+    let fee = tx::transaction_fee(miner, &tx)?;
+
+    // Every satoshi spent must show up as a payment, change or the fee
+    let outputs: Vec<Amount> = payments
+        .iter()
+        .map(|(_, amount)| *amount)
+        .chain(change.iter().map(|(output, _)| output.value))
+        .collect();
+    tx::verify_balance_equation(inputs, &outputs, fee)?;
+
+    let mut report = report::build_report(
+        &tx,
+        mining_address,
+        inputs,
+        fee,
+        &payments,
+        change.as_ref(),
+        block_height,
+    )?;
+    report.replaceable = decoded.is_explicitly_rbf();
+    report.set_metrics(tx::tx_metrics(&decoded));
+    report.set_output_types(&decoded);
+    report.set_op_return(&decoded);
+    log_change(&report);
+    if args.list_inputs {
+        report.inputs = tx::describe_inputs(miner, &decoded, args.network)?;
+    }
+    if args.include_raw {
+        report.set_raw_hex(&decoded)?;
+    }
+    if args.block_details {
+        report.block_details = Some(tx::block_details(&session.node, &block_hash)?);
+    }
+    // Not every way of sending can set a comment, so it may not be there
+    if let Some(sent) = &args.comment {
+        report.comment = tx::wallet_comment(&session.miner, &txid)?;
+        match &report.comment {
+            Some(kept) if kept == sent => info!("Wallet comment read back: {kept:?}"),
+            Some(kept) => warn!("the Miner wallet has comment {kept:?}, not {sent:?}"),
+            None => warn!("the Miner wallet didn't keep the comment {sent:?}"),
+        }
+    }
+    report.fee_bump = fee_bump;
+    report.resend = resend;
+    report.fan_out = fan_out;
+    report.funding_label = args.from_label.clone();
+    if args.coinbase_message.is_some() {
+        let block = session.node.get_block(&block_hash)?;
+        report.coinbase_message = mining::coinbase_message(&block)
+            .map(|message| String::from_utf8_lossy(&message).into_owned());
+    }
+    report.mempool = Some(mempool);
+    report.psbt = psbt;
+    log_batching(miner);
+    record_balances(&mut report, miner, trader)?;
+    if !args.balance_wallets.is_empty() {
+        let names: Vec<&str> = [&args.wallets.miner, &args.wallets.trader]
+            .into_iter()
+            .chain(&args.balance_wallets)
+            .map(String::as_str)
+            .collect();
+        let mut clients = session.wallet_clients.borrow_mut();
+        wallet::total_balance_across_wallets(&session.node, &names, &mut clients);
+    }
+
+    // Spend the received coins to show the Trader can
+    if args.round_trip {
+        report.return_leg = Some(send_back(session, args, mining_address)?);
+    }
+    Ok(report)
+}
+
+/// Send the Miner's whole balance to `outputs` fresh addresses of its own in
+/// equal shares, and mine until that confirms so the payment can spend them.
+fn fan_out(
+    session: &Session,
+    args: &cli::Args,
+    mining_address: &Address,
+    outputs: u32,
+) -> Result<report::FanOut, AppError> {
+    let miner = &session.miner;
+    let addresses = (0..outputs)
+        .map(|_| {
+            miner
+                .get_new_address(Some("Fan-out"), session.address_type)?
+                .require_network(args.network)
+                .map_err(AppError::from)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let balance = miner.get_balance(None, None)?;
+    let (txid, share) = send::fan_out(miner, &addresses, balance)?;
+    info!(
+        "Fanning out {} BTC into {outputs} coins of {} BTC in {txid}",
+        balance.to_btc(),
+        share.to_btc()
+    );
+    mining::wait_for_confirmation(
+        miner,
+        &txid,
+        1,
+        args.mines_locally().then_some(mining_address),
+        args.confirm_timeout,
+    )?;
+
+    let utxo_count = miner
+        .list_unspent(Some(1), None, None, None, None)?
+        .iter()
+        .filter(|utxo| utxo.spendable)
+        .count();
+    info!("Miner holds {utxo_count} spendable coins after the fan-out");
+    Ok(report::FanOut {
+        txid,
+        outputs,
+        share,
+        utxo_count,
+    })
+}
+
+/// Fill in both wallets' confirmed balances, now the payment is in a block.
+fn record_balances(
+    report: &mut TxReport,
+    miner: &impl RpcApi,
+    trader: &impl RpcApi,
+) -> Result<(), AppError> {
+    let (miner_balance, trader_balance) = (
+        miner.get_balance(Some(1), None)?,
+        trader.get_balance(Some(1), None)?,
+    );
+    info!(
+        "Balances after the payment: Miner {} BTC, Trader {} BTC",
+        miner_balance.to_btc(),
+        trader_balance.to_btc()
+    );
+    report.miner_balance_after = Some(miner_balance);
+    report.trader_balance_after = Some(trader_balance);
+    Ok(())
+}
+
+/// Have the Trader send half the payment back to a fresh Miner address, and
+/// mine it to `mining_address` until it's as deep as the payment was.
+fn send_back(
+    session: &Session,
+    args: &cli::Args,
+    mining_address: &Address,
+) -> Result<report::ReturnLeg, AppError> {
+    let (miner, trader) = (&session.miner, &session.trader);
+    let amount = args.amount / 2;
+
+    // Only confirmed coins count, so this also checks the payment arrived
+    wallet::check_funds(trader.get_balance(Some(1), None)?, amount)?;
+    let address = miner
+        .get_new_address(Some("Returned"), session.address_type)?
+        .require_network(args.network)?;
+    let options = send::SendOptions {
+        fee_rate: args.fee_rate,
+        ..Default::default()
+    };
+    let txid = send::send_to_address(trader, &address, amount, &options)?;
+    info!("Trader sent {} BTC back in {txid}", amount.to_btc());
+
+    mining::wait_for_confirmation(
+        trader,
+        &txid,
+        args.confirmations,
+        args.mines_locally().then_some(mining_address),
+        args.confirm_timeout,
+    )?;
+    let tx = trader.get_transaction(&txid, None)?;
+    let decoded = tx.transaction().map_err(bitcoincore_rpc::Error::from)?;
+    tx::verify_payment(&decoded, &address, amount)?;
+    let (block_height, block_hash) = tx::confirming_block(&session.node, &tx)?;
+
+    Ok(report::ReturnLeg {
+        txid,
+        address: address.to_string(),
+        amount,
+        fee: tx::transaction_fee(trader, &tx)?,
+        block_height,
+        block_hash,
+    })
+}
+
+/// The Miner coins carrying `label`, failing with [`AppError::LabelFunds`]
+/// unless together they can pay `total` and the fee.
+fn label_inputs(
+    miner: &impl RpcApi,
+    label: &str,
+    total: Amount,
+) -> Result<Vec<OutPoint>, AppError> {
+    let utxos = send::label_utxos(miner, label)?;
+    let have: Amount = utxos.iter().map(|(_, amount)| *amount).sum();
+    if wallet::check_funds(have, total).is_err() {
+        return Err(AppError::LabelFunds {
+            label: label.to_owned(),
+            have,
+            need: total + wallet::FEE_BUFFER,
+        });
+    }
+    info!(
+        "Paying from the {} coins labeled {label:?}, worth {} BTC",
+        utxos.len(),
+        have.to_btc()
+    );
+    Ok(utxos.into_iter().map(|(outpoint, _)| outpoint).collect())
+}
+
+/// Log how much of the inputs came back as change, warning if that change is
+/// dust: an output the node normally wouldn't relay.
+fn log_change(report: &TxReport) {
+    if let Some(percent) = report.change_percent {
+        info!("{percent:.2}% of the inputs came back as change");
+    }
+    if report.change_is_dust {
+        warn!(
+            "change of {} BTC is below the dust threshold for its output type; \
+             wallets normally add such change to the fee instead",
+            report.change_amount.to_btc()
+        );
+    }
+}
+
+/// The change output of `decoded`: the one paying `--change-address` if it
+/// was given, else the one other than `payments` the Miner wallet owns.
+///
+/// Fails if no output pays `--change-address`, or if the wallet owns more
+/// than one of the other outputs.
+fn change_output(
+    miner: &(impl RpcApi + Sync),
+    decoded: &Transaction,
+    payments: &[(Address, Amount)],
+    args: &cli::Args,
+) -> Result<Option<(TxOut, Address)>, tx::TxError> {
+    match &args.change_address {
+        Some(address) => tx::output_to(decoded, address)
+            .map(|output| Some((output.clone(), address.clone())))
+            .ok_or_else(|| tx::TxError::ChangeNotFound(address.clone())),
+        None => {
+            let payees: Vec<Address> = payments
+                .iter()
+                .map(|(address, _)| address.clone())
+                .collect();
+            tx::find_change_output(miner, decoded, &payees, args.network)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockRpc;
+    use bitcoincore_rpc::bitcoin::consensus::encode::serialize_hex;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::{
+        absolute, transaction, BlockHash, Network, OutPoint, ScriptBuf, Sequence, TxIn, Witness,
+    };
+    use serde_json::Value;
+
+    const MINER: &str = "bcrt1qz467uuwqquf9zs7w9g6ffkwzlg6x6fqprghn22";
+    const TRADER: &str = "bcrt1qpp98hdd7ewl82me4dqv2nm9hxx0duzq9faas3u";
+    const CHANGE: &str = "bcrt1qun4kphxm503fhl0utulkytee32muwtfqufn3kl";
+
+    fn address(s: &str) -> Address {
+        s.parse::<Address<_>>()
+            .unwrap()
+            .require_network(Network::Regtest)
+            .unwrap()
+    }
+
+    fn paying(inputs: &[OutPoint], outputs: &[(&str, u64)]) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: inputs
+                .iter()
+                .map(|outpoint| TxIn {
+                    previous_output: *outpoint,
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: outputs
+                .iter()
+                .map(|(addr, sats)| TxOut {
+                    value: Amount::from_sat(*sats),
+                    script_pubkey: address(addr).script_pubkey(),
+                })
+                .collect(),
+        }
+    }
+
+    fn address_info(is_mine: bool) -> Value {
+        json!({
+            "address": CHANGE,
+            "scriptPubKey": "",
+            "ismine": is_mine,
+            "iswatchonly": false,
+            "solvable": true,
+            "isscript": false,
+            "iswitness": true,
+            "labels": [],
+        })
+    }
+
+    /// The node and both wallets, knowing about a confirmed payment of 20 BTC
+    /// with change from a 50 BTC reward. Returns them with the payment txid.
+    fn confirmed_payment() -> (MockRpc, MockRpc, MockRpc, Txid) {
+        let reward = paying(&[], &[(MINER, 5_000_000_000)]);
+        let payment = paying(
+            &[OutPoint::new(reward.txid(), 0)],
+            &[(TRADER, 2_000_000_000), (CHANGE, 2_999_999_859)],
+        );
+        let block = BlockHash::from_byte_array([1; 32]);
+
+        let miner = MockRpc::new()
+            .on(
+                "gettransaction",
+                json!({
+                    "txid": payment.txid(),
+                    "confirmations": 1,
+                    "blockhash": block,
+                    "blockindex": 1,
+                    "blocktime": 0,
+                    "time": 0,
+                    "timereceived": 0,
+                    "bip125-replaceable": "no",
+                    "walletconflicts": [],
+                    "amount": -20.0,
+                    "fee": -0.00000141,
+                    "details": [],
+                    "hex": serialize_hex(&payment),
+                }),
+            )
+            .on("getrawtransaction", json!(serialize_hex(&payment)))
+            .on("getrawtransaction", json!(serialize_hex(&reward)))
+            .on("getaddressinfo", address_info(true));
+        let trader = MockRpc::new().on("getaddressinfo", address_info(true));
+        let node = MockRpc::new().on(
+            "getblockheader",
+            json!({
+                "hash": block,
+                "confirmations": 1,
+                "height": 102,
+                "version": 536870912,
+                "merkleroot": "00".repeat(32),
+                "time": 0,
+                "mediantime": 0,
+                "nonce": 0,
+                "bits": "207fffff",
+                "difficulty": 4.656542373906925e-10,
+                "chainwork": "00",
+                "nTx": 2,
+            }),
+        );
+        (node, miner, trader, payment.txid())
+    }
+
+    #[test]
+    fn batched_and_unbatched_reports_match() {
+        let args = cli::Args {
+            list_inputs: true,
+            ..Default::default()
+        };
+
+        let (node, miner, trader, txid) = confirmed_payment();
+        let unbatched = prefetch_report(&miner, &txid, false).unwrap();
+        let expected = rebuild_report(&node, &unbatched, &trader, &txid, None, &args).unwrap();
+        assert_eq!(unbatched.stats().requests, 0);
+
+        let (node, miner, trader, txid) = confirmed_payment();
+        let batched = prefetch_report(&miner, &txid, true).unwrap();
+        let report = rebuild_report(&node, &batched, &trader, &txid, None, &args).unwrap();
+
+        assert_eq!(report, expected);
+        assert_eq!(report.miner_address, MINER);
+        assert_eq!(report.miner_input_amount, Amount::from_sat(5_000_000_000));
+        // One request for the payment, one for the reward it spends, and
+        // each fetched once however often the report reads it
+        assert_eq!(miner.calls("batch"), 2);
+        assert_eq!(miner.calls("gettransaction"), 1);
+        assert_eq!(miner.calls("getrawtransaction"), 2);
+        assert!(batched.stats().round_trips_saved() > 0);
+    }
+
+    #[test]
+    fn report_of_a_txid_unknown_to_wallet_and_node_says_so() {
+        let (node, _, trader, txid) = confirmed_payment();
+        let node = node.on_error(
+            "getrawtransaction",
+            -5,
+            "No such mempool or blockchain transaction",
+        );
+        let args = cli::Args::default();
+        for batch in [false, true] {
+            let miner = MockRpc::new().on_error(
+                "gettransaction",
+                -5,
+                "Invalid or non-wallet transaction id",
+            );
+            let err = prefetch_report(&miner, &txid, batch)
+                .and_then(|miner| rebuild_report(&node, &miner, &trader, &txid, None, &args))
+                .unwrap_err();
+            assert!(matches!(err, AppError::Tx(tx::TxError::UnknownTransaction(t)) if t == txid));
+        }
+    }
+
+    #[test]
+    fn transaction_outside_the_wallet_gets_a_partial_report() {
+        let (node, wallet, trader, txid) = confirmed_payment();
+        let decoded = wallet.get_raw_transaction(&txid, None).unwrap();
+        let block = BlockHash::from_byte_array([1; 32]);
+        let node = node.on(
+            "getrawtransaction",
+            json!({
+                "hex": serialize_hex(&decoded),
+                "txid": txid,
+                "hash": decoded.wtxid(),
+                "size": decoded.total_size(),
+                "vsize": decoded.vsize(),
+                "version": 2,
+                "locktime": 0,
+                "vin": [],
+                "vout": [],
+                "blockhash": block,
+                "confirmations": 3,
+            }),
+        );
+        let miner =
+            MockRpc::new().on_error("gettransaction", -5, "Invalid or non-wallet transaction id");
+        let args = cli::Args::default();
+
+        let report = rebuild_report(&node, &miner, &trader, &txid, Some(&block), &args).unwrap();
+
+        assert_eq!(report.txid, txid);
+        assert!(report.fee_unknown);
+        assert_eq!(report.fee, Amount::ZERO);
+        assert_eq!(report.block_height, Some(102));
+        assert_eq!(report.block_hash, Some(block));
+        assert_eq!(report.confirmations, 3);
+        let outputs: Vec<_> = report
+            .recipients
+            .iter()
+            .map(|r| r.address.as_str())
+            .collect();
+        assert_eq!(outputs, [TRADER, CHANGE]);
+        assert_eq!(
+            node.last_args("getrawtransaction").unwrap()[2],
+            json!(block)
+        );
+
+        let mut text = Vec::new();
+        report.write_text(&mut text).unwrap();
+        assert_eq!(
+            String::from_utf8(text).unwrap().lines().nth(7),
+            Some("unknown")
+        );
+    }
+
+    #[test]
+    fn change_must_be_owned_and_unambiguous() {
+        const OTHER: &str = "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080";
+        let payments = [(address(TRADER), Amount::from_btc(20.0).unwrap())];
+        let payment = paying(
+            &[],
+            &[
+                (TRADER, 2_000_000_000),
+                (CHANGE, 2_999_999_859),
+                (OTHER, 1_000),
+            ],
+        );
+        let args = cli::Args::default();
+
+        // An output the wallet doesn't own isn't taken for change
+        let miner = MockRpc::new()
+            .on_args("getaddressinfo", &[json!(CHANGE)], address_info(true))
+            .on_args("getaddressinfo", &[json!(OTHER)], address_info(false));
+        let (output, addr) = change_output(&miner, &payment, &payments, &args)
+            .unwrap()
+            .unwrap();
+        assert_eq!(addr, address(CHANGE));
+        assert_eq!(output.value, Amount::from_sat(2_999_999_859));
+
+        let miner = MockRpc::new().on("getaddressinfo", address_info(true));
+        assert!(matches!(
+            change_output(&miner, &payment, &payments, &args),
+            Err(tx::TxError::AmbiguousChange(2))
+        ));
+
+        let args = cli::Args {
+            change_address: Some(address(MINER)),
+            ..Default::default()
+        };
+        assert!(matches!(
+            change_output(&miner, &payment, &payments, &args),
+            Err(tx::TxError::ChangeNotFound(a)) if a == address(MINER)
+        ));
+    }
+
+    #[test]
+    fn trader_balance_grows_by_the_payment() {
+        let (node, miner, trader, txid) = confirmed_payment();
+        let args = cli::Args::default();
+        let mut report = rebuild_report(&node, &miner, &trader, &txid, None, &args).unwrap();
+
+        // The Trader's balance before the payment confirmed, then after
+        let trader = trader
+            .on("getbalance", json!(5.0))
+            .on("getbalance", json!(25.0));
+        let before = trader.get_balance(Some(1), None).unwrap();
+        let miner = miner.on("getbalance", json!(29.99999859));
+        record_balances(&mut report, &miner, &trader).unwrap();
+
+        let after = report.trader_balance_after.unwrap();
+        assert_eq!(after - before, args.amount);
+        assert_eq!(after - before, report.trader_output_amount);
+        assert_eq!(
+            report.miner_balance_after,
+            Some(Amount::from_sat(2_999_999_859))
+        );
+        // Only confirmed coins count
+        assert_eq!(trader.last_args("getbalance").unwrap()[1], json!(1));
+    }
+
+    #[test]
+    fn slow_step_overruns_a_tiny_deadline() {
+        let node = MockRpc::new().on("getblockcount", json!(101));
+        let slow = move || {
+            thread::sleep(Duration::from_millis(500));
+            Ok(node.get_block_count()?)
+        };
+        assert!(matches!(
+            with_deadline(Some(Duration::from_millis(10)), slow),
+            Err(AppError::DeadlineExceeded(deadline)) if deadline == Duration::from_millis(10)
+        ));
+
+        let node = MockRpc::new().on("getblockcount", json!(101));
+        let quick = move || Ok(node.get_block_count()?);
+        assert_eq!(
+            with_deadline(Some(Duration::from_secs(5)), quick).unwrap(),
+            101
+        );
+    }
+}
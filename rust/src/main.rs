@@ -1,116 +1,112 @@
-use bitcoincore_rpc::bitcoin::{Address, Amount};
-use bitcoincore_rpc::{Auth, Client, RpcApi};
-use std::fs::File;
-use std::io::Write;
-use std::path::Path;
-
-const RPC_URL: &str = "http://127.0.0.1:18443";
-const RPC_USER: &str = "alice";
-const RPC_PASS: &str = "password";
-
-fn main() -> bitcoincore_rpc::Result<()> {
-    // Connect to RPC (base connection without wallet)
-    let rpc = Client::new(
-        RPC_URL,
-        Auth::UserPass(RPC_USER.to_owned(), RPC_PASS.to_owned()),
-    )?;
-
-    // Create wallets if they don't exist
-    let wallets = rpc.list_wallets()?;
-    if !wallets.contains(&"Miner".to_string()) {
-        rpc.create_wallet("Miner", None, None, None, None)?;
+use log::{info, LevelFilter};
+use rust::cli::Command;
+use rust::{cli, error, report, rpc, state, AppError, Config};
+use std::io;
+use std::process;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+fn main() {
+    let (command, options) = match cli::Args::from_env() {
+        Ok(parsed) => parsed,
+        Err(msg) => {
+            eprintln!("error: {msg}");
+            process::exit(error::EXIT_CONFIG);
+        }
+    };
+
+    // Status messages are logged at info level; RUST_LOG=debug shows each RPC
+    // step, and --quiet drops everything short of an error
+    let mut logger =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    if options.quiet {
+        logger.filter_level(LevelFilter::Error);
     }
-    if !wallets.contains(&"Trader".to_string()) {
-        rpc.create_wallet("Trader", None, None, None, None)?;
+    logger.init();
+    let mut rpc = rpc::load_config_with(options.network, &options.settings);
+    rpc.timeout = options.rpc_timeout;
+    let config = Config { rpc, options };
+
+    // Everything from connecting on counts towards --deadline-secs; a run
+    // that overruns it exits without waiting for the step it's stuck in
+    let started = Instant::now();
+    let deadline = config.options.deadline;
+    if let Err(err) = rust::with_deadline(deadline, move || {
+        dispatch(command.as_ref(), &config, started)
+    }) {
+        eprintln!("error: {err}");
+        process::exit(error::exit_code(&err));
     }
+    info!("Finished in {:.1}s", started.elapsed().as_secs_f64());
+}
 
-    // Create wallet-specific clients
-    let miner = Client::new(
-        &format!("{RPC_URL}/wallet/Miner"),
-        Auth::UserPass(RPC_USER.to_owned(), RPC_PASS.to_owned()),
-    )?;
-
-    let trader = Client::new(
-        &format!("{RPC_URL}/wallet/Trader"),
-        Auth::UserPass(RPC_USER.to_owned(), RPC_PASS.to_owned()),
-    )?;
-
-    // Generate mining address and mine blocks
-    let mining_address = miner
-        .get_new_address(Some("Mining Reward"), None)?
-        .assume_checked();
-
-    // Mine 101 blocks to mature the coinbase transaction
-    rpc.generate_to_address(101, &mining_address)?;
-
-    // Get miner balance
-    let miner_balance = miner.get_balance(None, None)?;
-    println!("Miner balance: {} BTC", miner_balance.to_btc());
-
-    // Generate trader address
-    let trader_address = trader
-        .get_new_address(Some("Received"), None)?
-        .assume_checked();
-
-    // Send 20 BTC from Miner to Trader
-    let txid = miner.send_to_address(
-        &trader_address,
-        Amount::from_btc(20.0)?,
-        None,
-        None,
-        None,
-        None,
-        None,
-        None,
-    )?;
-
-    // Mine 1 block to confirm the transaction
-    let block_hashes = rpc.generate_to_address(1, &mining_address)?;
-    let block_hash = block_hashes[0];
-
-    // Get block height
-    let block_height = rpc.get_block_count()?;
-
-    // Get transaction details
-    let tx = miner.get_transaction(&txid, None)?;
-    let decoded = miner.get_raw_transaction(&txid, None)?;
-
-    // Find change output (output that's not to trader)
-    let change_output = decoded
-        .output
-        .iter()
-        .find(|o| {
-            let script = &o.script_pubkey;
-            let addr = Address::from_script(script, bitcoincore_rpc::bitcoin::Network::Regtest)
-                .unwrap_or_else(|_| panic!("Failed to convert script to address"));
-            addr != trader_address
-        })
-        .expect("Change output not found");
-
-    let change_address = Address::from_script(
-        &change_output.script_pubkey,
-        bitcoincore_rpc::bitcoin::Network::Regtest,
-    )
-    .expect("Failed to convert change script to address");
-
-    // Calculate fee (absolute value)
-    let fee = tx.fee.unwrap().to_btc().abs();
-
-    // Write to out.txt in the project root directory
-    let out_path = Path::new("../out.txt");
-    let mut file = File::create(out_path)?;
-    writeln!(file, "{txid}")?;
-    writeln!(file, "{mining_address}")?;
-    writeln!(file, "50")?; // Miner's input amount (block reward)
-    writeln!(file, "{trader_address}")?;
-    writeln!(file, "20")?; // Trader's output amount
-    writeln!(file, "{change_address}")?;
-    writeln!(file, "{}", change_output.value.to_btc())?;
-    writeln!(file, "{fee}")?;
-    writeln!(file, "{block_height}")?;
-    writeln!(file, "{block_hash}")?;
+/// Run the requested stage, or the whole flow, writing out any report.
+fn dispatch(command: Option<&Command>, config: &Config, started: Instant) -> Result<(), AppError> {
+    let options = &config.options;
+
+    // With --once a payment that already went through isn't made again
+    let pays = matches!(command, None | Some(Command::Pay));
+    let once = options.once.as_deref().filter(|_| pays);
+    if let Some(path) = once {
+        if let Some(txid) = rust::completed_payment(config, path)? {
+            info!(
+                "Payment {txid} recorded in {} is already confirmed, nothing to do",
+                path.display()
+            );
+            return Ok(());
+        }
+    }
 
-    println!("Transaction details written to out.txt successfully");
+    let mut report = match command {
+        None => rust::run(config)?,
+        Some(Command::Setup) => {
+            let mined = rust::setup(config)?;
+            info!("Setup complete, mined {mined} blocks");
+            return Ok(());
+        }
+        Some(Command::Pay) => rust::pay(config)?,
+        Some(Command::Report { txid, block_hash }) => {
+            rust::report(config, txid, block_hash.as_ref())?
+        }
+    };
+
+    report.elapsed_secs = Some(started.elapsed().as_secs_f64());
+
+    // A dry run only prints what the report would say; with --json-stdout
+    // that's the JSON line alone, so stdout stays machine-readable
+    if options.dry_run {
+        if options.json_stdout {
+            report.write_json_line(&mut io::stdout())?;
+        } else {
+            report.write(options.format, &mut io::stdout())?;
+        }
+        return Ok(());
+    }
 
+    // Write to out.txt in the project root directory (or wherever --out says),
+    // whole or not at all, for anyone reading it meanwhile
+    let mut rendered = Vec::new();
+    report.write(options.format, &mut rendered)?;
+    report::write_atomic(&options.out, &rendered)?;
+
+    info!(
+        "Transaction details written to {} successfully",
+        options.out.display()
+    );
+
+    if let Some(csv) = &options.csv {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        report.append_csv(csv, now)?;
+        info!("Appended transaction to {}", csv.display());
+    }
+    // Logs go to stderr, so this is all a pipeline reading stdout sees
+    if options.json_stdout {
+        report.write_json_line(&mut io::stdout())?;
+    }
+    if let Some(path) = once {
+        state::save_state(path, &report.txid)?;
+        info!("Recorded payment {} in {}", report.txid, path.display());
+    }
     Ok(())
 }
@@ -0,0 +1,880 @@
+use crate::cli::COINBASE_MATURITY;
+use crate::tx::TxError;
+use bitcoincore_rpc::bitcoin::hashes::Hash;
+use bitcoincore_rpc::bitcoin::script::{self, Instruction, PushBytesBuf};
+use bitcoincore_rpc::bitcoin::{
+    absolute, block, transaction, Address, Amount, Block, BlockHash, CompactTarget, Network,
+    OutPoint, Sequence, Transaction, TxIn, TxMerkleNode, TxOut, Txid, Witness,
+};
+use bitcoincore_rpc::json::{GetBlockTemplateModes, GetBlockTemplateResult, GetBlockTemplateRules};
+use bitcoincore_rpc::RpcApi;
+use log::{debug, info};
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Pause between polls when a freshly mined block didn't pick up the transaction.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Upper bound on blocks mined one at a time while topping up a balance.
+const MAX_TOP_UP_BLOCKS: u64 = 1_000;
+
+/// Blocks mined between balance checks while waiting for a reward to mature.
+const MATURITY_BATCH: u64 = 10;
+
+/// Mining progress is logged each time another this many blocks are done.
+const PROGRESS_INTERVAL: u64 = 25;
+
+/// Failures while mining blocks for the Miner wallet.
+#[derive(Debug)]
+pub enum MiningError {
+    /// Block generation was requested on a network that doesn't allow it.
+    Disabled(Network),
+    /// The wallet still couldn't spend the needed amount after mining.
+    NotSpendable { blocks: u64 },
+    /// `generatetoaddress` returned fewer block hashes than were asked for.
+    NoBlockGenerated { requested: u64, generated: usize },
+    /// A block's first transaction isn't a coinbase.
+    NoCoinbase(BlockHash),
+    /// The wallet can't pay on a network whose blocks can't be mined
+    /// locally, so it has to be funded from elsewhere first.
+    FundExternally {
+        network: Network,
+        address: Address,
+        have: Amount,
+        need: Amount,
+    },
+    /// Blocks mined elsewhere didn't fund the wallet in time.
+    FundsTimeout {
+        have: Amount,
+        need: Amount,
+        waited: Duration,
+    },
+    /// A block couldn't be built from the node's template.
+    BadTemplate(String),
+    /// The node rejected an RPC call.
+    Rpc(bitcoincore_rpc::Error),
+}
+
+impl fmt::Display for MiningError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MiningError::Disabled(network) => write!(
+                f,
+                "mining is disabled on {network}: blocks can't be generated on demand there"
+            ),
+            MiningError::NotSpendable { blocks } => {
+                write!(
+                    f,
+                    "balance still not spendable after mining {blocks} blocks"
+                )
+            }
+            MiningError::NoBlockGenerated {
+                requested,
+                generated,
+            } => write!(
+                f,
+                "asked the node for {requested} blocks but it generated {generated}"
+            ),
+            MiningError::NoCoinbase(hash) => write!(f, "block {hash} has no coinbase transaction"),
+            MiningError::FundExternally {
+                network,
+                address,
+                have,
+                need,
+            } => write!(
+                f,
+                "blocks can't be mined locally on {network}, so fund the Miner externally \
+                 (e.g. from a faucet): it holds {} BTC but needs {} BTC, send the rest to {address}",
+                have.to_btc(),
+                need.to_btc()
+            ),
+            MiningError::FundsTimeout { have, need, waited } => write!(
+                f,
+                "the wallet holds {} BTC but needs {} BTC, and nothing mined elsewhere \
+                 made up the difference within {}s",
+                have.to_btc(),
+                need.to_btc(),
+                waited.as_secs()
+            ),
+            MiningError::BadTemplate(reason) => {
+                write!(f, "can't build a block from the node's template: {reason}")
+            }
+            MiningError::Rpc(err) => write!(f, "RPC error while mining: {err}"),
+        }
+    }
+}
+
+impl Error for MiningError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MiningError::Rpc(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<bitcoincore_rpc::Error> for MiningError {
+    fn from(err: bitcoincore_rpc::Error) -> Self {
+        MiningError::Rpc(err)
+    }
+}
+
+/// Refuse to mine on networks where `generatetoaddress` can't work.
+pub fn check_network(network: Network) -> Result<(), MiningError> {
+    match network {
+        Network::Bitcoin => Err(MiningError::Disabled(network)),
+        _ => Ok(()),
+    }
+}
+
+/// Whether blocks on `network` can be mined on demand by this node.
+///
+/// Signet blocks must be signed by the network's challenge key, so a signet
+/// node can only wait for them.
+pub fn mines_locally(network: Network) -> bool {
+    !matches!(network, Network::Bitcoin | Network::Signet)
+}
+
+/// Check that `miner` can already pay `needed` without any mining, for
+/// networks where it can't mine its own coins. Fails with
+/// [`MiningError::FundExternally`], naming `addr` to send coins to.
+pub fn require_external_funds(
+    miner: &impl RpcApi,
+    addr: &Address,
+    needed: Amount,
+    network: Network,
+) -> Result<(), MiningError> {
+    let have = miner.get_balance(None, None)?;
+    if have < needed {
+        return Err(MiningError::FundExternally {
+            network,
+            address: addr.clone(),
+            have,
+            need: needed,
+        });
+    }
+    Ok(())
+}
+
+/// Wait for blocks mined by someone else to leave `miner` able to spend
+/// `needed`, polling its balance until `timeout` runs out. Returns the
+/// balance, or fails with [`MiningError::FundsTimeout`].
+pub fn wait_for_funds(
+    miner: &impl RpcApi,
+    needed: Amount,
+    timeout: Duration,
+) -> Result<Amount, MiningError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let have = miner.get_balance(None, None)?;
+        if have >= needed {
+            return Ok(have);
+        }
+        if Instant::now() >= deadline {
+            return Err(MiningError::FundsTimeout {
+                have,
+                need: needed,
+                waited: timeout,
+            });
+        }
+        debug!(
+            "waiting for {} BTC to become spendable, have {}",
+            needed.to_btc(),
+            have.to_btc()
+        );
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Mine `count` blocks to `addr`, returning their hashes.
+///
+/// Fails with [`MiningError::NoBlockGenerated`] unless the node reports all
+/// of them, so callers can index into the result.
+pub fn generate_blocks(
+    rpc: &impl RpcApi,
+    count: u64,
+    addr: &Address,
+) -> Result<Vec<BlockHash>, MiningError> {
+    check_generated(rpc.generate_to_address(count, addr)?, count)
+}
+
+/// Mine one block to `addr` with `message` in its coinbase, which
+/// `generatetoaddress` can't do: the block is built from the node's
+/// `getblocktemplate`, with whatever its mempool holds, and handed back with
+/// `submitblock`.
+pub fn mine_block_with_message(
+    rpc: &impl RpcApi,
+    addr: &Address,
+    message: &[u8],
+) -> Result<BlockHash, MiningError> {
+    let template = rpc.get_block_template(
+        GetBlockTemplateModes::Template,
+        &[GetBlockTemplateRules::SegWit],
+        &[],
+    )?;
+    let block = build_block(&template, addr, message)?;
+    let hash = block.block_hash();
+    debug!("submitting block {hash} at height {}", template.height);
+    rpc.submit_block(&block)?;
+    Ok(hash)
+}
+
+/// Build the block `template` describes, paying its coinbase to `addr` with
+/// `message` pushed after the height in the coinbase's scriptSig, and grind
+/// the nonce until the header meets the target. That's only quick on
+/// regtest, where about every other hash does.
+pub fn build_block(
+    template: &GetBlockTemplateResult,
+    addr: &Address,
+    message: &[u8],
+) -> Result<Block, MiningError> {
+    let message = PushBytesBuf::try_from(message.to_vec())
+        .map_err(|_| MiningError::BadTemplate("coinbase message too long".to_owned()))?;
+    // BIP34 wants the height first
+    let script_sig = script::Builder::new()
+        .push_int(template.height as i64)
+        .push_slice(message)
+        .into_script();
+
+    let mut output = vec![TxOut {
+        value: template.coinbase_value,
+        script_pubkey: addr.script_pubkey(),
+    }];
+    let mut witness = Witness::new();
+    if !template.default_witness_commitment.is_empty() {
+        // The node's commitment assumes an all-zero witness reserved value
+        output.push(TxOut {
+            value: Amount::ZERO,
+            script_pubkey: template.default_witness_commitment.clone(),
+        });
+        witness.push([0u8; 32]);
+    }
+    let coinbase = Transaction {
+        version: transaction::Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig,
+            sequence: Sequence::MAX,
+            witness,
+        }],
+        output,
+    };
+
+    let mut txdata = vec![coinbase];
+    for tx in &template.transactions {
+        let decoded = tx
+            .transaction()
+            .map_err(|err| MiningError::BadTemplate(format!("transaction {}: {err}", tx.txid)))?;
+        txdata.push(decoded);
+    }
+    let bits = <[u8; 4]>::try_from(template.bits.as_slice())
+        .map_err(|_| MiningError::BadTemplate(format!("{} bytes of bits", template.bits.len())))?;
+    let mut block = Block {
+        header: block::Header {
+            version: block::Version::from_consensus(template.version as i32),
+            prev_blockhash: template.previous_block_hash,
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: template.current_time as u32,
+            bits: CompactTarget::from_consensus(u32::from_be_bytes(bits)),
+            nonce: 0,
+        },
+        txdata,
+    };
+    block.header.merkle_root = block
+        .compute_merkle_root()
+        .expect("the block holds at least the coinbase");
+
+    let target = block.header.target();
+    while block.header.validate_pow(target).is_err() {
+        block.header.nonce = block
+            .header
+            .nonce
+            .checked_add(1)
+            .ok_or_else(|| MiningError::BadTemplate("no nonce meets the target".to_owned()))?;
+    }
+    Ok(block)
+}
+
+/// The message a block's coinbase carries after the height, as put there by
+/// [`build_block`]; `None` if there's none, as with `generatetoaddress`.
+pub fn coinbase_message(block: &Block) -> Option<Vec<u8>> {
+    let coinbase = block.txdata.first().filter(|tx| tx.is_coinbase())?;
+    let mut pushes = coinbase.input[0]
+        .script_sig
+        .instructions()
+        .flatten()
+        .skip(1);
+    match pushes.next()? {
+        Instruction::PushBytes(bytes) if !bytes.is_empty() => Some(bytes.as_bytes().to_vec()),
+        _ => None,
+    }
+}
+
+/// What the coinbase of block `block_hash` paid out: the subsidy plus fees,
+/// read from the block rather than assumed, since regtest halves the subsidy
+/// every 150 blocks.
+pub fn coinbase_reward(rpc: &impl RpcApi, block_hash: &BlockHash) -> Result<Amount, MiningError> {
+    let block = rpc.get_block(block_hash)?;
+    let coinbase = block
+        .txdata
+        .first()
+        .filter(|tx| tx.is_coinbase())
+        .ok_or(MiningError::NoCoinbase(*block_hash))?;
+    Ok(coinbase.output.iter().map(|output| output.value).sum())
+}
+
+/// What a wallet that received every coinbase of a fresh chain should hold
+/// after `blocks_generated` blocks: one `subsidy` per block buried under
+/// [`COINBASE_MATURITY`] more, so 101 blocks mature exactly one. Halvings
+/// aren't accounted for.
+pub fn expected_matured_balance(blocks_generated: u64, subsidy: Amount) -> Amount {
+    subsidy * blocks_generated.saturating_sub(COINBASE_MATURITY)
+}
+
+/// Rewind the chain to genesis by invalidating block 1, so the next blocks
+/// mined start a fresh chain. Returns how many blocks were rewound: none if
+/// the chain is already at genesis.
+///
+/// Only meant for regtest, where the chain is disposable.
+pub fn reset_chain(rpc: &impl RpcApi) -> Result<u64, MiningError> {
+    let height = rpc.get_block_count()?;
+    if height == 0 {
+        return Ok(0);
+    }
+    let first = rpc.get_block_hash(1)?;
+    debug!("invalidating block {first} and the {} after it", height - 1);
+    rpc.invalidate_block(&first)?;
+    Ok(height)
+}
+
+fn check_generated(hashes: Vec<BlockHash>, requested: u64) -> Result<Vec<BlockHash>, MiningError> {
+    if (hashes.len() as u64) < requested {
+        return Err(MiningError::NoBlockGenerated {
+            requested,
+            generated: hashes.len(),
+        });
+    }
+    Ok(hashes)
+}
+
+/// Make sure `miner` can spend at least `needed`, mining to `addr` via `rpc`
+/// only as far as necessary. Returns the number of blocks mined.
+///
+/// A wallet that's already funded (e.g. on a second run) mines nothing. One
+/// with no coinbase rewards on the way first mines until a reward matures, up
+/// to `maturity_blocks`; after that blocks are mined one at a time, each
+/// maturing one more reward.
+pub fn ensure_spendable(
+    miner: &impl RpcApi,
+    rpc: &impl RpcApi,
+    addr: &Address,
+    needed: Amount,
+    maturity_blocks: u64,
+) -> Result<u64, MiningError> {
+    if miner.get_balance(None, None)? >= needed {
+        debug!(
+            "wallet already holds {} BTC, skipping mining",
+            needed.to_btc()
+        );
+        return Ok(0);
+    }
+
+    let mut mined = 0;
+    if miner.get_balances()?.mine.immature == Amount::ZERO {
+        mined += mine_until_spendable(rpc, miner, addr, maturity_blocks)?;
+    }
+    while miner.get_balance(None, None)? < needed {
+        if mined >= maturity_blocks + MAX_TOP_UP_BLOCKS {
+            return Err(MiningError::NotSpendable { blocks: mined });
+        }
+        debug!(
+            "balance below {} BTC, mining one more block",
+            needed.to_btc()
+        );
+        generate_blocks(rpc, 1, addr)?;
+        mined += 1;
+    }
+    Ok(mined)
+}
+
+/// Mine to `addr` in small batches until `miner` has a spendable balance,
+/// finding out how deep coinbase rewards must be buried on this chain rather
+/// than assuming regtest's 100 blocks. Returns the number of blocks mined.
+///
+/// Fails with [`MiningError::NotSpendable`] if nothing has matured after
+/// `max_blocks`.
+pub fn mine_until_spendable(
+    rpc: &impl RpcApi,
+    miner: &impl RpcApi,
+    addr: &Address,
+    max_blocks: u64,
+) -> Result<u64, MiningError> {
+    let mut mined = 0;
+    while mined < max_blocks {
+        let batch = MATURITY_BATCH.min(max_blocks - mined);
+        debug!("generating {batch} blocks to {addr}");
+        generate_blocks(rpc, batch, addr)?;
+        if progress_due(mined, mined + batch) {
+            info!("Mined {} of up to {max_blocks} blocks", mined + batch);
+        }
+        mined += batch;
+        if miner.get_balance(None, None)? > Amount::ZERO {
+            debug!("first reward matured after {mined} blocks");
+            return Ok(mined);
+        }
+    }
+    Err(MiningError::NotSpendable { blocks: mined })
+}
+
+/// Whether going from `before` to `after` blocks mined passes another
+/// multiple of [`PROGRESS_INTERVAL`].
+fn progress_due(before: u64, after: u64) -> bool {
+    after / PROGRESS_INTERVAL > before / PROGRESS_INTERVAL
+}
+
+/// Mine blocks to `addr` until `txid` has at least `target` confirmations.
+/// With no `addr` nothing is mined, for when something else grows the chain
+/// (see [`start_block_generator`]); the transaction is just polled.
+///
+/// Gives up with [`TxError::ConfirmationTimeout`] once `timeout` elapses, and
+/// with [`TxError::Conflicted`] if the wallet reports the transaction as
+/// conflicted. Returns the confirmation count that was finally observed.
+pub fn wait_for_confirmation(
+    client: &impl RpcApi,
+    txid: &Txid,
+    target: u32,
+    addr: Option<&Address>,
+    timeout: Duration,
+) -> Result<u32, TxError> {
+    let deadline = Instant::now() + timeout;
+    let mut mined_last_round = false;
+    loop {
+        let confirmations = client.get_transaction(txid, None)?.info.confirmations;
+        if confirmations < 0 {
+            return Err(TxError::Conflicted(*txid));
+        }
+        let confirmations = confirmations as u32;
+        debug!("transaction {txid} has {confirmations}/{target} confirmations");
+        if confirmations >= target {
+            return Ok(confirmations);
+        }
+        if Instant::now() >= deadline {
+            return Err(TxError::ConfirmationTimeout {
+                txid: *txid,
+                confirmations,
+                target,
+            });
+        }
+
+        // A block that left the tx unconfirmed means it isn't in our mempool
+        // (yet); wait instead of growing the chain needlessly
+        match addr {
+            Some(addr) if !(mined_last_round && confirmations == 0) => {
+                debug!("mining a block to confirm {txid}");
+                generate_blocks(client, 1, addr)?;
+                mined_last_round = true;
+            }
+            _ => {
+                thread::sleep(POLL_INTERVAL);
+                mined_last_round = false;
+            }
+        }
+    }
+}
+
+/// A thread mining blocks at a steady pace, started by
+/// [`start_block_generator`].
+///
+/// Dropping the handle stops the thread too, discarding its result.
+pub struct GeneratorHandle {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<Result<u64, MiningError>>>,
+}
+
+impl GeneratorHandle {
+    /// Stop mining and wait for the thread to finish. Returns the number of
+    /// blocks it mined, or the error that made it stop early.
+    pub fn stop(mut self) -> Result<u64, MiningError> {
+        self.join()
+    }
+
+    fn join(&mut self) -> Result<u64, MiningError> {
+        self.stop.store(true, Ordering::Relaxed);
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(panic)) => std::panic::resume_unwind(panic),
+            None => Ok(0),
+        }
+    }
+}
+
+impl Drop for GeneratorHandle {
+    fn drop(&mut self) {
+        if let Err(err) = self.join() {
+            debug!("block generator failed: {err}");
+        }
+    }
+}
+
+/// Mine a block to `addr` every `interval` on a background thread, like a
+/// live chain would, until the returned handle is stopped or dropped.
+///
+/// The thread stops at the first failed RPC; [`GeneratorHandle::stop`]
+/// reports the error.
+pub fn start_block_generator<R>(rpc: R, addr: Address, interval: Duration) -> GeneratorHandle
+where
+    R: RpcApi + Send + 'static,
+{
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = Arc::clone(&stop);
+    let thread = thread::spawn(move || {
+        let mut mined = 0;
+        loop {
+            // Sleep in short steps so stopping doesn't wait out a long interval
+            let next = Instant::now() + interval;
+            while Instant::now() < next {
+                if stopped.load(Ordering::Relaxed) {
+                    return Ok(mined);
+                }
+                thread::sleep(POLL_INTERVAL.min(next.saturating_duration_since(Instant::now())));
+            }
+            generate_blocks(&rpc, 1, &addr)?;
+            mined += 1;
+            debug!("background generator mined block {mined}");
+        }
+    });
+    GeneratorHandle {
+        stop,
+        thread: Some(thread),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockRpc;
+    use bitcoincore_rpc::bitcoin::consensus::encode::{deserialize, serialize_hex};
+    use bitcoincore_rpc::bitcoin::constants::genesis_block;
+    use bitcoincore_rpc::bitcoin::hash_types::WitnessMerkleNode;
+    use bitcoincore_rpc::bitcoin::hex::FromHex;
+    use bitcoincore_rpc::bitcoin::ScriptBuf;
+    use serde_json::json;
+
+    fn regtest_address() -> Address {
+        "bcrt1qz467uuwqquf9zs7w9g6ffkwzlg6x6fqprghn22"
+            .parse::<Address<_>>()
+            .unwrap()
+            .assume_checked()
+    }
+
+    /// A `generatetoaddress` answer long enough for any batch the helpers mine.
+    fn generated() -> serde_json::Value {
+        json!(vec![BlockHash::all_zeros(); MATURITY_BATCH as usize])
+    }
+
+    #[test]
+    fn generator_mines_until_stopped() {
+        let rpc = MockRpc::new().on("generatetoaddress", generated());
+        let generator = start_block_generator(rpc, regtest_address(), Duration::from_millis(1));
+        thread::sleep(Duration::from_millis(50));
+        assert!(generator.stop().unwrap() > 0);
+    }
+
+    #[test]
+    fn generator_surfaces_rpc_errors() {
+        let generator =
+            start_block_generator(MockRpc::new(), regtest_address(), Duration::from_millis(1));
+        thread::sleep(Duration::from_millis(50));
+        assert!(matches!(generator.stop(), Err(MiningError::Rpc(_))));
+    }
+
+    #[test]
+    fn empty_block_list_is_an_error() {
+        assert!(matches!(
+            check_generated(Vec::new(), 1),
+            Err(MiningError::NoBlockGenerated {
+                requested: 1,
+                generated: 0
+            })
+        ));
+        let hashes = vec![BlockHash::all_zeros()];
+        assert_eq!(check_generated(hashes.clone(), 1).unwrap(), hashes);
+    }
+
+    #[test]
+    fn funds_from_an_external_miner_are_waited_for() {
+        let needed = Amount::from_btc(20.001).unwrap();
+        let miner = MockRpc::new()
+            .on("getbalance", json!(0.0))
+            .on("getbalance", json!(50.0));
+        let have = wait_for_funds(&miner, needed, Duration::from_secs(5)).unwrap();
+        assert_eq!(have, Amount::from_btc(50.0).unwrap());
+        assert_eq!(miner.calls("getbalance"), 2);
+
+        let miner = MockRpc::new().on("getbalance", json!(0.0));
+        assert!(matches!(
+            wait_for_funds(&miner, needed, Duration::ZERO),
+            Err(MiningError::FundsTimeout { have, need, .. })
+                if have == Amount::ZERO && need == needed
+        ));
+    }
+
+    #[test]
+    fn one_coinbase_matures_after_101_blocks() {
+        let subsidy = Amount::from_btc(50.0).unwrap();
+        assert_eq!(expected_matured_balance(101, subsidy), subsidy);
+        assert_eq!(expected_matured_balance(110, subsidy), subsidy * 10);
+        assert_eq!(expected_matured_balance(100, subsidy), Amount::ZERO);
+        assert_eq!(expected_matured_balance(0, subsidy), Amount::ZERO);
+    }
+
+    /// Regtest's `getblocktemplate` for block 1, with `transactions` from its
+    /// mempool.
+    fn template(transactions: &[Transaction], witness_commitment: &ScriptBuf) -> serde_json::Value {
+        let transactions: Vec<_> = transactions
+            .iter()
+            .map(|tx| {
+                json!({
+                    "data": serialize_hex(tx),
+                    "txid": tx.txid(),
+                    "hash": tx.wtxid(),
+                    "fee": 141,
+                    "sigops": 4,
+                    "weight": tx.weight().to_wu(),
+                    "depends": [],
+                })
+            })
+            .collect();
+        json!({
+            "capabilities": ["proposal"],
+            "version": 0x2000_0000,
+            "rules": ["csv", "!segwit", "taproot"],
+            "vbavailable": {},
+            "vbrequired": 0,
+            "previousblockhash": genesis_block(Network::Regtest).block_hash(),
+            "transactions": transactions,
+            "coinbaseaux": {},
+            "coinbasevalue": 5_000_000_141u64,
+            "longpollid": "",
+            "target": "7fffff0000000000000000000000000000000000000000000000000000000000",
+            "mintime": 1_296_688_603,
+            "mutable": ["time", "transactions", "prevblock"],
+            "noncerange": "00000000ffffffff",
+            "sigoplimit": 80_000,
+            "sizelimit": 4_000_000,
+            "weightlimit": 4_000_000,
+            "curtime": 1_296_688_700,
+            "bits": "207fffff",
+            "height": 1,
+            "default_witness_commitment": witness_commitment.to_hex_string(),
+        })
+    }
+
+    #[test]
+    fn block_with_a_coinbase_message_is_built_and_submitted() {
+        // With nothing but the coinbase the witness root is all zeros
+        let commitment =
+            Block::compute_witness_commitment(&WitnessMerkleNode::all_zeros(), &[0u8; 32]);
+        let mut data = vec![0xaa, 0x21, 0xa9, 0xed];
+        data.extend_from_slice(commitment.as_byte_array());
+        let commitment = ScriptBuf::new_op_return(PushBytesBuf::try_from(data).unwrap());
+
+        let rpc = MockRpc::new()
+            .on("getblocktemplate", template(&[], &commitment))
+            .on("submitblock", serde_json::Value::Null);
+        let hash = mine_block_with_message(&rpc, &regtest_address(), b"hello, regtest").unwrap();
+
+        let submitted = rpc.last_args("submitblock").unwrap();
+        let raw = Vec::<u8>::from_hex(submitted[0].as_str().unwrap()).unwrap();
+        let block: Block = deserialize(&raw).unwrap();
+        assert_eq!(block.block_hash(), hash);
+        assert!(block.header.validate_pow(block.header.target()).is_ok());
+        assert!(block.check_merkle_root());
+        assert!(block.check_witness_commitment());
+        assert_eq!(
+            block.txdata[0].output[0].value,
+            Amount::from_sat(5_000_000_141)
+        );
+        assert_eq!(
+            block.txdata[0].output[0].script_pubkey,
+            regtest_address().script_pubkey()
+        );
+        assert_eq!(coinbase_message(&block), Some(b"hello, regtest".to_vec()));
+    }
+
+    #[test]
+    fn template_transactions_follow_the_coinbase() {
+        let genesis = genesis_block(Network::Regtest);
+        let payment = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(genesis.txdata[0].txid(), 0),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::from_btc(20.0).unwrap(),
+                script_pubkey: regtest_address().script_pubkey(),
+            }],
+        };
+        let template: GetBlockTemplateResult =
+            serde_json::from_value(template(std::slice::from_ref(&payment), &ScriptBuf::new()))
+                .unwrap();
+        let block = build_block(&template, &regtest_address(), b"hi").unwrap();
+        assert_eq!(block.txdata.len(), 2);
+        assert_eq!(block.txdata[1], payment);
+        assert!(block.check_merkle_root());
+        assert!(block.txdata[0].input[0].witness.is_empty());
+
+        // Like generatetoaddress, pushing nothing after the height is no message
+        let block = build_block(&template, &regtest_address(), b"").unwrap();
+        assert_eq!(coinbase_message(&block), None);
+    }
+
+    #[test]
+    fn coinbase_reward_is_read_from_the_block() {
+        let mut block = genesis_block(Network::Regtest);
+        let hash = block.block_hash();
+        let rpc = MockRpc::new().on("getblock", json!(serialize_hex(&block)));
+        assert_eq!(
+            coinbase_reward(&rpc, &hash).unwrap(),
+            Amount::from_btc(50.0).unwrap()
+        );
+        assert_eq!(rpc.last_args("getblock").unwrap(), [json!(hash), json!(0)]);
+
+        // A halved subsidy split over two outputs, as with a witness commitment
+        block.txdata[0].output[0].value = Amount::from_btc(12.5).unwrap();
+        block.txdata[0].output.push(TxOut {
+            value: Amount::from_sat(141),
+            script_pubkey: ScriptBuf::new_op_return([0xaa]),
+        });
+        let rpc = MockRpc::new().on("getblock", json!(serialize_hex(&block)));
+        assert_eq!(
+            coinbase_reward(&rpc, &hash).unwrap(),
+            Amount::from_sat(1_250_000_141)
+        );
+
+        block.txdata.clear();
+        let rpc = MockRpc::new().on("getblock", json!(serialize_hex(&block)));
+        assert!(matches!(
+            coinbase_reward(&rpc, &hash),
+            Err(MiningError::NoCoinbase(h)) if h == hash
+        ));
+    }
+
+    #[test]
+    fn already_funded_wallet_mines_nothing() {
+        let miner = MockRpc::new().on("getbalance", json!(79.99999859));
+        let rpc = MockRpc::new();
+
+        let needed = Amount::from_btc(20.001).unwrap();
+        let mined = ensure_spendable(&miner, &rpc, &regtest_address(), needed, 101).unwrap();
+
+        assert_eq!(mined, 0);
+        assert_eq!(rpc.calls("generatetoaddress"), 0);
+    }
+
+    #[test]
+    fn signet_wallet_must_be_funded_externally() {
+        assert!(!mines_locally(Network::Signet));
+        assert!(mines_locally(Network::Regtest));
+
+        let address = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx"
+            .parse::<Address<_>>()
+            .unwrap()
+            .require_network(Network::Signet)
+            .unwrap();
+        let miner = MockRpc::new()
+            .on("getbalance", json!(0.5))
+            .on("getbalance", json!(25.0));
+        let needed = Amount::from_btc(20.001).unwrap();
+
+        let err = require_external_funds(&miner, &address, needed, Network::Signet).unwrap_err();
+        assert!(
+            matches!(err, MiningError::FundExternally { have, .. } if have == Amount::from_btc(0.5).unwrap())
+        );
+        let msg = err.to_string();
+        assert!(msg.contains("fund the Miner externally") && msg.contains(&address.to_string()));
+
+        assert!(require_external_funds(&miner, &address, needed, Network::Signet).is_ok());
+    }
+
+    /// A wallet whose balance stays at zero for `empty_checks` balance checks.
+    fn maturing_wallet(empty_checks: usize) -> MockRpc {
+        let mut miner = MockRpc::new().on(
+            "getbalances",
+            json!({"mine": {"trusted": 0.0, "untrusted_pending": 0.0, "immature": 0.0}}),
+        );
+        for _ in 0..empty_checks {
+            miner = miner.on("getbalance", json!(0.0));
+        }
+        miner.on("getbalance", json!(50.0))
+    }
+
+    #[test]
+    fn empty_wallet_mines_to_maturity() {
+        // The initial check plus one after each of the first ten batches
+        let miner = maturing_wallet(11);
+        let rpc = MockRpc::new().on("generatetoaddress", generated());
+
+        let needed = Amount::from_btc(20.001).unwrap();
+        let mined = ensure_spendable(&miner, &rpc, &regtest_address(), needed, 101).unwrap();
+
+        // Ten batches of ten, then one more block up to the limit
+        assert_eq!(mined, 101);
+        assert_eq!(rpc.calls("generatetoaddress"), 11);
+    }
+
+    #[test]
+    fn progress_is_logged_every_25_blocks() {
+        // Batches of ten log at 30, 50, 80 and 100 blocks
+        assert!(!progress_due(0, 10) && !progress_due(10, 20));
+        assert!(progress_due(20, 30));
+        assert!(!progress_due(30, 40));
+        assert!(progress_due(40, 50));
+        assert!(progress_due(0, 25) && progress_due(99, 101));
+    }
+
+    #[test]
+    fn mining_stops_once_a_reward_matures() {
+        let miner = maturing_wallet(2);
+        let rpc = MockRpc::new().on("generatetoaddress", generated());
+
+        let mined = mine_until_spendable(&rpc, &miner, &regtest_address(), 500).unwrap();
+        assert_eq!(mined, 30);
+    }
+
+    #[test]
+    fn reset_invalidates_the_first_block_unless_at_genesis() {
+        let first = "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206";
+        let rpc = MockRpc::new()
+            .on("getblockcount", json!(150))
+            .on("getblockcount", json!(0))
+            .on("getblockhash", json!(first))
+            .on("invalidateblock", json!(null));
+
+        assert_eq!(reset_chain(&rpc).unwrap(), 150);
+        assert_eq!(rpc.last_args("getblockhash").unwrap(), [json!(1)]);
+        assert_eq!(rpc.last_args("invalidateblock").unwrap(), [json!(first)]);
+
+        // Already at genesis there's nothing to invalidate
+        assert_eq!(reset_chain(&rpc).unwrap(), 0);
+        assert_eq!(rpc.calls("invalidateblock"), 1);
+    }
+
+    #[test]
+    fn reward_not_maturing_within_limit_is_an_error() {
+        let miner = MockRpc::new().on("getbalance", json!(0.0));
+        let rpc = MockRpc::new().on("generatetoaddress", generated());
+
+        assert!(matches!(
+            mine_until_spendable(&rpc, &miner, &regtest_address(), 25),
+            Err(MiningError::NotSpendable { blocks: 25 })
+        ));
+    }
+}
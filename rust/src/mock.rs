@@ -0,0 +1,149 @@
+//! A scripted stand-in for the node, used to unit test RPC-driven helpers.
+
+use crate::rpc::BatchRpc;
+use bitcoincore_rpc::jsonrpc::error::RpcError;
+use bitcoincore_rpc::{jsonrpc, RpcApi};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// Answers RPC calls from canned JSON responses.
+///
+/// Responses registered for a method are handed out in order; the last one
+/// keeps being returned once the others are used up. Calling a method with
+/// no responses fails like an unknown RPC would. A response registered for
+/// particular arguments answers every call with those, ahead of the queue,
+/// so calls made concurrently get the right answers.
+#[derive(Default)]
+pub struct MockRpc {
+    responses: Mutex<HashMap<String, VecDeque<Result<Value, RpcError>>>>,
+    by_args: Mutex<HashMap<(String, Vec<Value>), Value>>,
+    calls: Mutex<Vec<(String, Vec<Value>)>>,
+}
+
+impl MockRpc {
+    pub fn new() -> Self {
+        MockRpc::default()
+    }
+
+    /// Queue `response` as the next result of `method`.
+    pub fn on(self, method: &str, response: Value) -> Self {
+        self.respond(method, Ok(response))
+    }
+
+    /// Queue an RPC error with `code` and `message` as the next result of
+    /// `method`, as the node reports a failed call.
+    pub fn on_error(self, method: &str, code: i32, message: &str) -> Self {
+        self.respond(
+            method,
+            Err(RpcError {
+                code,
+                message: message.to_owned(),
+                data: None,
+            }),
+        )
+    }
+
+    /// Answer every call of `method` with exactly `args` with `response`.
+    pub fn on_args(self, method: &str, args: &[Value], response: Value) -> Self {
+        self.by_args
+            .lock()
+            .unwrap()
+            .insert((method.to_owned(), args.to_vec()), response);
+        self
+    }
+
+    fn respond(self, method: &str, response: Result<Value, RpcError>) -> Self {
+        self.responses
+            .lock()
+            .unwrap()
+            .entry(method.to_owned())
+            .or_default()
+            .push_back(response);
+        self
+    }
+
+    /// Number of times `method` was called.
+    pub fn calls(&self, method: &str) -> usize {
+        self.calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(name, _)| name == method)
+            .count()
+    }
+
+    /// Arguments of every call to `method`, oldest first.
+    pub fn all_args(&self, method: &str) -> Vec<Vec<Value>> {
+        self.calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(name, _)| name == method)
+            .map(|(_, args)| args.clone())
+            .collect()
+    }
+
+    /// Arguments of the most recent call to `method`.
+    pub fn last_args(&self, method: &str) -> Option<Vec<Value>> {
+        self.calls
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|(name, _)| name == method)
+            .map(|(_, args)| args.clone())
+    }
+}
+
+impl RpcApi for MockRpc {
+    fn call<T: for<'a> serde::de::Deserialize<'a>>(
+        &self,
+        cmd: &str,
+        args: &[Value],
+    ) -> bitcoincore_rpc::Result<T> {
+        self.calls
+            .lock()
+            .unwrap()
+            .push((cmd.to_owned(), args.to_vec()));
+
+        let keyed = self
+            .by_args
+            .lock()
+            .unwrap()
+            .get(&(cmd.to_owned(), args.to_vec()))
+            .cloned();
+        if let Some(response) = keyed {
+            return Ok(serde_json::from_value(response)?);
+        }
+        let mut responses = self.responses.lock().unwrap();
+        let queue = responses
+            .get_mut(cmd)
+            .filter(|q| !q.is_empty())
+            .ok_or_else(|| {
+                bitcoincore_rpc::Error::ReturnedError(format!("unexpected RPC call: {cmd}"))
+            })?;
+        let response = if queue.len() > 1 {
+            queue.pop_front().unwrap()
+        } else {
+            queue[0].clone()
+        };
+        let response = response.map_err(jsonrpc::Error::Rpc)?;
+        Ok(serde_json::from_value(response)?)
+    }
+}
+
+impl BatchRpc for MockRpc {
+    /// Answers each call in turn, recording the batch itself as a call to
+    /// `batch` ahead of them.
+    fn call_batch(&self, calls: &[(&str, Vec<Value>)]) -> bitcoincore_rpc::Result<Vec<Value>> {
+        self.calls
+            .lock()
+            .unwrap()
+            .push(("batch".to_owned(), Vec::new()));
+        calls
+            .iter()
+            .map(|(method, args)| self.call(method, args))
+            .collect()
+    }
+}
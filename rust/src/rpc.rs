@@ -0,0 +1,648 @@
+use crate::settings::{self, Settings};
+use crate::AppError;
+use bitcoincore_rpc::bitcoin::{Address, Network};
+use bitcoincore_rpc::json::GetBlockchainInfoResult;
+use bitcoincore_rpc::jsonrpc::simple_http::SimpleHttpTransport;
+use bitcoincore_rpc::{jsonrpc, Auth, Client, RpcApi};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::panic;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_RPC_HOST: &str = "http://127.0.0.1";
+const DEFAULT_RPC_USER: &str = "alice";
+const DEFAULT_RPC_PASS: &str = "password";
+
+/// How long a single RPC request may take before it fails.
+pub const DEFAULT_RPC_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Oldest node version (v0.21.0) that can create descriptor wallets.
+const MIN_DESCRIPTOR_VERSION: usize = 210_000;
+
+/// Connection settings for the bitcoind RPC server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RpcConfig {
+    pub url: String,
+    pub user: String,
+    pub pass: String,
+    /// Cookie file to authenticate with instead of `user`/`pass`.
+    pub cookie: Option<PathBuf>,
+    /// Limit on each RPC request; one that takes longer fails with a
+    /// transport error instead of hanging.
+    pub timeout: Duration,
+}
+
+/// Read a variable from the environment, treating an empty value as unset.
+pub(crate) fn env_var(key: &str) -> Option<String> {
+    env::var(key).ok().filter(|value| !value.is_empty())
+}
+
+/// Bitcoin Core's default RPC port for `network`.
+fn default_port(network: Network) -> u16 {
+    match network {
+        Network::Bitcoin => 8332,
+        Network::Testnet => 18332,
+        Network::Signet => 38332,
+        _ => 18443,
+    }
+}
+
+/// Load RPC settings from `BITCOIN_RPC_URL`, `BITCOIN_RPC_USER` and
+/// `BITCOIN_RPC_PASS`, falling back to a local node on `network`'s default
+/// port. Setting `BITCOIN_RPC_COOKIE` switches to cookie-file authentication.
+pub fn load_config(network: Network) -> RpcConfig {
+    load_config_from(network, env_var)
+}
+
+/// [`load_config`], with the `[rpc]` table of a `--config` file filling in
+/// for variables the environment leaves unset.
+pub fn load_config_with(network: Network, file: &Settings) -> RpcConfig {
+    load_config_from(network, settings::layered(env_var, file))
+}
+
+/// Build the config from `lookup`'s values for the `BITCOIN_RPC_*` variables.
+pub(crate) fn load_config_from(
+    network: Network,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> RpcConfig {
+    RpcConfig {
+        url: lookup("BITCOIN_RPC_URL")
+            .unwrap_or_else(|| format!("{DEFAULT_RPC_HOST}:{}", default_port(network))),
+        user: lookup("BITCOIN_RPC_USER").unwrap_or_else(|| DEFAULT_RPC_USER.to_owned()),
+        pass: lookup("BITCOIN_RPC_PASS").unwrap_or_else(|| DEFAULT_RPC_PASS.to_owned()),
+        cookie: lookup("BITCOIN_RPC_COOKIE").map(PathBuf::from),
+        timeout: DEFAULT_RPC_TIMEOUT,
+    }
+}
+
+/// Pick the authentication method for `config`, preferring the cookie file.
+fn auth(config: &RpcConfig) -> bitcoincore_rpc::Result<Auth> {
+    match &config.cookie {
+        Some(path) if !path.exists() => Err(bitcoincore_rpc::Error::Io(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("RPC cookie file not found: {}", path.display()),
+        ))),
+        Some(path) => Ok(Auth::CookieFile(path.clone())),
+        None => Ok(Auth::UserPass(config.user.clone(), config.pass.clone())),
+    }
+}
+
+/// Build the RPC endpoint, scoped to `wallet` when one is given.
+fn endpoint(base: &str, wallet: Option<&str>) -> String {
+    match wallet {
+        Some(name) => format!("{base}/wallet/{name}"),
+        None => base.to_owned(),
+    }
+}
+
+/// Open an RPC client to the node, optionally bound to a loaded wallet.
+///
+/// Built on the HTTP transport directly, since [`Client::new`] offers no way
+/// to set `config.timeout`.
+pub fn connect(config: &RpcConfig, wallet: Option<&str>) -> bitcoincore_rpc::Result<Client> {
+    let (user, pass) = auth(config)?.get_user_pass()?;
+    let mut builder = SimpleHttpTransport::builder()
+        .url(&endpoint(&config.url, wallet))
+        .map_err(|err| bitcoincore_rpc::Error::JsonRpc(err.into()))?
+        .timeout(config.timeout);
+    if let Some(user) = user {
+        builder = builder.auth(user, pass);
+    }
+    Ok(Client::from_jsonrpc(jsonrpc::Client::with_transport(
+        builder.build(),
+    )))
+}
+
+/// Open the base (wallet-less) connection, retrying while the node starts up.
+///
+/// Each attempt builds the client and issues a cheap `getblockchaininfo`; the
+/// pause between attempts starts at `delay` and doubles every time. Returns the
+/// last error once all `attempts` have failed.
+pub fn connect_with_retry(
+    config: &RpcConfig,
+    attempts: u32,
+    delay: Duration,
+) -> bitcoincore_rpc::Result<Client> {
+    let mut delay = delay;
+    let mut attempt = 1;
+    loop {
+        let result = connect(config, None).and_then(|client| {
+            client.get_blockchain_info()?;
+            Ok(client)
+        });
+        match result {
+            Ok(client) => return Ok(client),
+            Err(err) if attempt >= attempts => return Err(err),
+            Err(err) => {
+                debug!("connection attempt {attempt}/{attempts} failed: {err}");
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Opens the connection to a wallet, given its name.
+type Connector<C> = Box<dyn Fn(&str) -> bitcoincore_rpc::Result<C>>;
+
+/// Wallet connections kept for reuse, keyed by wallet name, so asking for
+/// the same wallet again doesn't open a new connection to it.
+pub struct WalletClients<C = Client> {
+    connect: Connector<C>,
+    clients: HashMap<String, C>,
+}
+
+impl WalletClients {
+    /// Connect to wallets on the node `config` points at as they're asked for.
+    pub fn new(config: &RpcConfig) -> Self {
+        let config = config.clone();
+        WalletClients::with_connect(move |name| connect(&config, Some(name)))
+    }
+}
+
+impl<C> WalletClients<C> {
+    /// Open each wallet's connection with `connect` the first time it's
+    /// asked for.
+    pub fn with_connect(connect: impl Fn(&str) -> bitcoincore_rpc::Result<C> + 'static) -> Self {
+        WalletClients {
+            connect: Box::new(connect),
+            clients: HashMap::new(),
+        }
+    }
+
+    /// The connection to the wallet `name`, opened now if it wasn't yet.
+    pub fn get(&mut self, name: &str) -> bitcoincore_rpc::Result<&C> {
+        Ok(match self.clients.entry(name.to_owned()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                debug!("connecting to wallet {name}");
+                entry.insert((self.connect)(name)?)
+            }
+        })
+    }
+}
+
+/// Check that the node at `rpc` runs the `expected` chain before anything
+/// touches its wallets, warning if it's too old for descriptor wallets.
+/// A mainnet node is refused unless `allow_mainnet` is set.
+pub fn preflight(
+    rpc: &impl RpcApi,
+    expected: Network,
+    allow_mainnet: bool,
+) -> Result<(), AppError> {
+    let version = rpc.get_network_info()?.version;
+    let info = rpc.get_blockchain_info()?;
+    assert_safe_network(&info, allow_mainnet)?;
+    let chain = info.chain;
+    check_node(version, chain, expected)?;
+    info!("Connected to node version {version} on {chain}");
+    Ok(())
+}
+
+/// What `validateaddress` says about an address.
+#[derive(Debug, Deserialize)]
+struct AddressValidation {
+    isvalid: bool,
+    /// Why the address is invalid, from nodes since v0.21.
+    #[serde(default)]
+    error: Option<String>,
+}
+
+/// Have the node check `address` with `validateaddress`, failing with
+/// [`AppError::RejectedAddress`] if it doesn't consider it valid.
+pub fn validate_address(rpc: &impl RpcApi, address: &Address) -> Result<(), AppError> {
+    let validation: AddressValidation =
+        rpc.call("validateaddress", &[address.to_string().into()])?;
+    if !validation.isvalid {
+        return Err(AppError::RejectedAddress {
+            address: address.to_string(),
+            reason: validation
+                .error
+                .unwrap_or_else(|| "invalid address".to_owned()),
+        });
+    }
+    Ok(())
+}
+
+/// Refuse a node on mainnet, where real coins are at stake, unless
+/// `allow_mainnet` says that's intended.
+pub fn assert_safe_network(
+    info: &GetBlockchainInfoResult,
+    allow_mainnet: bool,
+) -> Result<(), AppError> {
+    if info.chain == Network::Bitcoin && !allow_mainnet {
+        return Err(AppError::MainnetRefused);
+    }
+    Ok(())
+}
+
+fn check_node(version: usize, chain: Network, expected: Network) -> Result<(), AppError> {
+    if chain != expected {
+        return Err(AppError::WrongChain {
+            expected,
+            actual: chain,
+        });
+    }
+    if version < MIN_DESCRIPTOR_VERSION {
+        warn!(
+            "node version {version} predates descriptor wallets \
+             (needs {MIN_DESCRIPTOR_VERSION}); pass --legacy-wallets"
+        );
+    }
+    Ok(())
+}
+
+/// Call `f` on every one of `items`, each on its own thread, and return the
+/// results in the order of `items` however the threads finish. A panic in
+/// `f` is passed on.
+pub(crate) fn par_map<T: Sync, U: Send>(items: &[T], f: impl Fn(&T) -> U + Sync) -> Vec<U> {
+    let f = &f;
+    thread::scope(|scope| {
+        let handles: Vec<_> = items
+            .iter()
+            .map(|item| scope.spawn(move || f(item)))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|panic| panic::resume_unwind(panic))
+            })
+            .collect()
+    })
+}
+
+/// A client that can send several RPC calls in one JSON-RPC batch request.
+pub trait BatchRpc: RpcApi {
+    /// Send every `(method, args)` of `calls` in a single request and return
+    /// their results in the same order. Any call failing fails the batch.
+    fn call_batch(&self, calls: &[(&str, Vec<Value>)]) -> bitcoincore_rpc::Result<Vec<Value>>;
+}
+
+impl BatchRpc for Client {
+    fn call_batch(&self, calls: &[(&str, Vec<Value>)]) -> bitcoincore_rpc::Result<Vec<Value>> {
+        let client = self.get_jsonrpc_client();
+        let params = calls
+            .iter()
+            .map(|(_, args)| args.iter().map(serde_json::value::to_raw_value).collect())
+            .collect::<Result<Vec<Vec<_>>, _>>()?;
+        let requests: Vec<_> = calls
+            .iter()
+            .zip(&params)
+            .map(|((method, _), params)| client.build_request(method, params))
+            .collect();
+        client
+            .send_batch(&requests)?
+            .into_iter()
+            .map(|response| {
+                let response = response.ok_or(jsonrpc::Error::WrongBatchResponseSize)?;
+                Ok(response.result::<Value>()?)
+            })
+            .collect()
+    }
+}
+
+/// Wraps a client to answer calls from results fetched ahead of time in
+/// batches, passing every other call on unchanged.
+///
+/// Code reading through it gets the same answers whether or not anything was
+/// prefetched, only with fewer round trips.
+pub struct Prefetched<'a, C> {
+    client: &'a C,
+    /// Results keyed by method and JSON-encoded arguments.
+    results: Mutex<HashMap<(String, String), Value>>,
+    stats: Mutex<BatchStats>,
+}
+
+/// What batching saved a [`Prefetched`] client.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BatchStats {
+    /// Batch requests sent.
+    pub requests: usize,
+    /// Calls answered from batched results instead of their own request.
+    pub hits: usize,
+    /// Time spent waiting on the batch requests.
+    pub elapsed: Duration,
+}
+
+impl BatchStats {
+    /// Round trips avoided by batching.
+    pub fn round_trips_saved(&self) -> usize {
+        self.hits.saturating_sub(self.requests)
+    }
+
+    /// Estimated time saved, taking every avoided round trip to cost as much
+    /// as an average batch request did.
+    pub fn time_saved(&self) -> Duration {
+        match self.requests {
+            0 => Duration::ZERO,
+            requests => self.elapsed / requests as u32 * self.round_trips_saved() as u32,
+        }
+    }
+}
+
+impl<'a, C: BatchRpc> Prefetched<'a, C> {
+    pub fn new(client: &'a C) -> Self {
+        Prefetched {
+            client,
+            results: Mutex::default(),
+            stats: Mutex::default(),
+        }
+    }
+
+    /// Fetch `calls` in one batch request, keeping the results for later
+    /// calls with the same method and arguments.
+    ///
+    /// The arguments must be exactly what the matching [`RpcApi`] method
+    /// sends, trailing defaults left off, or the result is never used.
+    pub fn prefetch(&self, calls: &[(&str, Vec<Value>)]) -> bitcoincore_rpc::Result<()> {
+        if calls.is_empty() {
+            return Ok(());
+        }
+        let started = Instant::now();
+        let fetched = self.client.call_batch(calls)?;
+        let mut stats = self.stats.lock().unwrap();
+        stats.requests += 1;
+        stats.elapsed += started.elapsed();
+
+        let mut results = self.results.lock().unwrap();
+        for ((method, args), result) in calls.iter().zip(fetched) {
+            results.insert(cache_key(method, args), result);
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> BatchStats {
+        *self.stats.lock().unwrap()
+    }
+}
+
+impl<C: RpcApi> RpcApi for Prefetched<'_, C> {
+    fn call<T: for<'de> serde::Deserialize<'de>>(
+        &self,
+        cmd: &str,
+        args: &[Value],
+    ) -> bitcoincore_rpc::Result<T> {
+        let cached = self
+            .results
+            .lock()
+            .unwrap()
+            .get(&cache_key(cmd, args))
+            .cloned();
+        match cached {
+            Some(result) => {
+                self.stats.lock().unwrap().hits += 1;
+                Ok(serde_json::from_value(result)?)
+            }
+            None => self.client.call(cmd, args),
+        }
+    }
+}
+
+fn cache_key(method: &str, args: &[Value]) -> (String, String) {
+    (method.to_owned(), Value::from(args.to_vec()).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockRpc;
+    use serde_json::json;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // Tests touching process-wide environment variables must not overlap.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    const VARS: [&str; 4] = [
+        "BITCOIN_RPC_URL",
+        "BITCOIN_RPC_USER",
+        "BITCOIN_RPC_PASS",
+        "BITCOIN_RPC_COOKIE",
+    ];
+
+    const REGTEST_URL: &str = "http://127.0.0.1:18443";
+
+    fn defaults() -> RpcConfig {
+        RpcConfig {
+            url: REGTEST_URL.to_owned(),
+            user: DEFAULT_RPC_USER.to_owned(),
+            pass: DEFAULT_RPC_PASS.to_owned(),
+            cookie: None,
+            timeout: DEFAULT_RPC_TIMEOUT,
+        }
+    }
+
+    fn clear_env() {
+        for var in VARS {
+            env::remove_var(var);
+        }
+    }
+
+    #[test]
+    fn endpoint_without_wallet_is_base_url() {
+        assert_eq!(endpoint(REGTEST_URL, None), "http://127.0.0.1:18443");
+    }
+
+    #[test]
+    fn endpoint_with_wallet_appends_wallet_path() {
+        assert_eq!(
+            endpoint(REGTEST_URL, Some("Miner")),
+            "http://127.0.0.1:18443/wallet/Miner"
+        );
+    }
+
+    #[test]
+    fn load_config_reads_env_and_falls_back_to_defaults() {
+        let _guard = ENV_LOCK.lock().unwrap();
+
+        clear_env();
+        assert_eq!(load_config(Network::Regtest), defaults());
+
+        env::set_var("BITCOIN_RPC_URL", "http://node:8332");
+        env::set_var("BITCOIN_RPC_USER", "bob");
+        env::set_var("BITCOIN_RPC_PASS", "hunter2");
+        assert_eq!(
+            load_config(Network::Regtest),
+            RpcConfig {
+                url: "http://node:8332".to_owned(),
+                user: "bob".to_owned(),
+                pass: "hunter2".to_owned(),
+                cookie: None,
+                timeout: DEFAULT_RPC_TIMEOUT,
+            }
+        );
+
+        // An empty value means "unset", not an empty password.
+        env::set_var("BITCOIN_RPC_PASS", "");
+        assert_eq!(load_config(Network::Regtest).pass, DEFAULT_RPC_PASS);
+
+        env::set_var("BITCOIN_RPC_COOKIE", "/tmp/regtest/.cookie");
+        assert_eq!(
+            load_config(Network::Regtest).cookie,
+            Some(PathBuf::from("/tmp/regtest/.cookie"))
+        );
+
+        clear_env();
+        assert_eq!(load_config(Network::Regtest), defaults());
+    }
+
+    #[test]
+    fn default_url_uses_network_port() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        clear_env();
+        assert_eq!(load_config(Network::Signet).url, "http://127.0.0.1:38332");
+        assert_eq!(load_config(Network::Bitcoin).url, "http://127.0.0.1:8332");
+    }
+
+    #[test]
+    fn retry_gives_up_with_last_error() {
+        let config = RpcConfig {
+            url: "http://127.0.0.1:1".to_owned(),
+            ..defaults()
+        };
+        assert!(connect_with_retry(&config, 3, Duration::from_millis(1)).is_err());
+    }
+
+    #[test]
+    fn unresponsive_node_times_out() {
+        // Accepts the connection but never answers
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = RpcConfig {
+            url: format!("http://{}", listener.local_addr().unwrap()),
+            timeout: Duration::from_millis(200),
+            ..defaults()
+        };
+
+        let started = std::time::Instant::now();
+        let client = connect(&config, None).unwrap();
+        assert!(client.get_block_count().is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn same_wallet_is_connected_once() {
+        let opened = Rc::new(Cell::new(0));
+        let counter = Rc::clone(&opened);
+        let mut clients = WalletClients::with_connect(move |_| {
+            counter.set(counter.get() + 1);
+            Ok(MockRpc::new())
+        });
+
+        let first: *const MockRpc = clients.get("Miner").unwrap();
+        let second: *const MockRpc = clients.get("Miner").unwrap();
+        assert_eq!(first, second);
+        assert_eq!(opened.get(), 1);
+
+        clients.get("Trader").unwrap();
+        assert_eq!(opened.get(), 2);
+    }
+
+    #[test]
+    fn node_rejected_address_is_an_error() {
+        let address = "bcrt1qpp98hdd7ewl82me4dqv2nm9hxx0duzq9faas3u"
+            .parse::<Address<_>>()
+            .unwrap()
+            .assume_checked();
+        let node = MockRpc::new()
+            .on(
+                "validateaddress",
+                json!({"isvalid": true, "address": address}),
+            )
+            .on(
+                "validateaddress",
+                json!({"isvalid": false, "error": "Invalid Bech32 checksum"}),
+            );
+
+        assert!(validate_address(&node, &address).is_ok());
+        assert_eq!(node.last_args("validateaddress").unwrap(), [json!(address)]);
+        let err = validate_address(&node, &address).unwrap_err();
+        assert!(matches!(
+            &err,
+            AppError::RejectedAddress { reason, .. } if reason == "Invalid Bech32 checksum"
+        ));
+    }
+
+    #[test]
+    fn node_on_another_chain_is_rejected() {
+        assert!(check_node(250_000, Network::Regtest, Network::Regtest).is_ok());
+        // Too old for descriptor wallets only warns
+        assert!(check_node(200_000, Network::Regtest, Network::Regtest).is_ok());
+
+        let err = check_node(250_000, Network::Testnet, Network::Regtest).unwrap_err();
+        assert!(matches!(
+            err,
+            AppError::WrongChain {
+                expected: Network::Regtest,
+                actual: Network::Testnet
+            }
+        ));
+    }
+
+    fn blockchain_info(chain: &str) -> GetBlockchainInfoResult {
+        serde_json::from_value(json!({
+            "chain": chain,
+            "blocks": 0,
+            "headers": 0,
+            "bestblockhash": "0000000000000000000000000000000000000000000000000000000000000000",
+            "difficulty": 1.0,
+            "mediantime": 0,
+            "verificationprogress": 1.0,
+            "initialblockdownload": false,
+            "chainwork": "00",
+            "size_on_disk": 0,
+            "pruned": false,
+            "warnings": "",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn mainnet_is_refused_unless_allowed() {
+        for chain in ["regtest", "test", "signet"] {
+            assert!(assert_safe_network(&blockchain_info(chain), false).is_ok());
+            assert!(assert_safe_network(&blockchain_info(chain), true).is_ok());
+        }
+        let main = blockchain_info("main");
+        assert!(matches!(
+            assert_safe_network(&main, false),
+            Err(AppError::MainnetRefused)
+        ));
+        assert!(assert_safe_network(&main, true).is_ok());
+    }
+
+    #[test]
+    fn par_map_keeps_the_order_of_its_items() {
+        // The later the item, the sooner its thread finishes
+        let items: Vec<u64> = (0..64).collect();
+        let started = Instant::now();
+        let doubled = par_map(&items, |&i| {
+            thread::sleep(Duration::from_millis(64 - i));
+            i * 2
+        });
+        let expected: Vec<u64> = items.iter().map(|i| i * 2).collect();
+        assert_eq!(doubled, expected);
+        // Together, not the two seconds one after another would take
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn missing_cookie_file_is_reported() {
+        let config = RpcConfig {
+            cookie: Some(PathBuf::from("/nonexistent/.cookie")),
+            ..defaults()
+        };
+        let err = auth(&config).unwrap_err();
+        assert!(err.to_string().contains("/nonexistent/.cookie"));
+    }
+}
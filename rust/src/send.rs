@@ -0,0 +1,878 @@
+use crate::tx::{self, TxError};
+use crate::wallet;
+use bitcoincore_rpc::bitcoin::consensus::encode;
+use bitcoincore_rpc::bitcoin::hex::DisplayHex;
+use bitcoincore_rpc::bitcoin::{Address, Amount, FeeRate, OutPoint, Sequence, Transaction, Txid};
+use bitcoincore_rpc::json::{
+    CreateRawTransactionInput, FundRawTransactionOptions, WalletCreateFundedPsbtOptions,
+};
+use bitcoincore_rpc::{jsonrpc, RpcApi};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+/// Bitcoin Core's `RPC_IN_WARMUP`: the node is still loading.
+const RPC_IN_WARMUP: i32 = -28;
+/// Bitcoin Core's `RPC_VERIFY_REJECTED`, which covers a full mempool.
+const RPC_VERIFY_REJECTED: i32 = -26;
+
+/// Times a send is tried before giving up on a transient failure.
+pub const SEND_ATTEMPTS: u32 = 3;
+/// Pause before the first retry of a send; it doubles after each one.
+pub const SEND_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Confirmation target, in blocks, of the fee estimate checked before sending.
+pub const FEE_ESTIMATE_TARGET: u16 = 6;
+/// Fee rate in sat/vB above which an estimate is logged as a spike.
+const FEE_SPIKE_WARNING: f64 = 100.0;
+
+/// Wallet options applied to the payment transaction.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SendOptions {
+    /// Explicit fee rate in sat/vB; `None` leaves fee estimation to the node.
+    pub fee_rate: Option<f64>,
+    /// Signal BIP125 replaceability so the payment can be fee-bumped later.
+    pub replaceable: bool,
+    /// Where the change goes; `None` lets the wallet pick a fresh address.
+    pub change_address: Option<Address>,
+    /// Coins to spend, and no others; empty lets the wallet choose.
+    pub inputs: Vec<OutPoint>,
+    /// Data to embed in a zero-value `OP_RETURN` output.
+    pub op_return: Option<Vec<u8>>,
+    /// Note the wallet keeps with the transaction. Only `sendtoaddress` and
+    /// `sendmany` can set one; the other ways of sending drop it.
+    pub comment: Option<String>,
+}
+
+/// The base64 PSBT of a payment after each step of the PSBT workflow.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PsbtStages {
+    /// As created and funded by `walletcreatefundedpsbt`.
+    pub funded: String,
+    /// After `walletprocesspsbt` added the wallet's signatures.
+    pub signed: String,
+}
+
+/// Pay every `(address, amount)` pair in `payments` from `wallet`.
+///
+/// A single payment goes through `sendtoaddress`; several are batched into
+/// one transaction with `sendmany`. Neither can be told where the change
+/// goes, which coins to spend or what data to embed, so with any of those set
+/// the transaction is funded, signed and broadcast step by step instead.
+pub fn send_payments(
+    wallet: &impl RpcApi,
+    payments: &[(Address, Amount)],
+    options: &SendOptions,
+) -> Result<Txid, TxError> {
+    if options.change_address.is_some() || !options.inputs.is_empty() || options.op_return.is_some()
+    {
+        return send_funded(wallet, payments, options);
+    }
+    Ok(match payments {
+        [(address, amount)] => send_to_address(wallet, address, *amount, options)?,
+        _ => send_many(wallet, payments, options)?,
+    })
+}
+
+/// Pay `amount` to `address` with `sendtoaddress`.
+///
+/// Called directly rather than through [`RpcApi::send_to_address`], which
+/// has no way to pass `fee_rate`.
+pub fn send_to_address(
+    wallet: &impl RpcApi,
+    address: &Address,
+    amount: Amount,
+    options: &SendOptions,
+) -> bitcoincore_rpc::Result<Txid> {
+    // address, amount, comment, comment_to, subtractfeefromamount,
+    // replaceable, conf_target, estimate_mode, avoid_reuse, fee_rate
+    let mut args = vec![address.to_string().into(), json!(amount.to_btc())];
+    if let Some(comment) = &options.comment {
+        set_arg(&mut args, 2, json!(comment));
+    }
+    if options.replaceable {
+        set_arg(&mut args, 5, json!(true));
+    }
+    if let Some(fee_rate) = options.fee_rate {
+        set_arg(&mut args, 9, json!(fee_rate));
+    }
+    debug!("sendtoaddress {address} {} BTC", amount.to_btc());
+    let txid = wallet.call("sendtoaddress", &args)?;
+    debug!("broadcast transaction {txid}");
+    Ok(txid)
+}
+
+/// Pay all `payments` in a single transaction using the `sendmany` RPC.
+pub fn send_many(
+    wallet: &impl RpcApi,
+    payments: &[(Address, Amount)],
+    options: &SendOptions,
+) -> bitcoincore_rpc::Result<Txid> {
+    let amounts: Map<String, Value> = payments
+        .iter()
+        .map(|(address, amount)| (address.to_string(), json!(amount.to_btc())))
+        .collect();
+    // The first argument is the long-deprecated "dummy" account; the rest are
+    // minconf, comment, subtractfeefrom, replaceable, conf_target,
+    // estimate_mode and fee_rate
+    let mut args = vec!["".into(), Value::Object(amounts)];
+    if let Some(comment) = &options.comment {
+        set_arg(&mut args, 3, json!(comment));
+    }
+    if options.replaceable {
+        set_arg(&mut args, 4, json!(true));
+    }
+    if let Some(fee_rate) = options.fee_rate {
+        set_arg(&mut args, 8, json!(fee_rate));
+    }
+    debug!("sendmany to {} recipients", payments.len());
+    let txid = wallet.call("sendmany", &args)?;
+    debug!("broadcast transaction {txid}");
+    Ok(txid)
+}
+
+/// Split `balance` evenly across `addresses` in one `sendmany` transaction.
+/// The fee comes out of the outputs rather than on top of them, so the whole
+/// balance can be spent. Returns the txid and each output's share before the
+/// fee was taken.
+pub fn fan_out(
+    wallet: &impl RpcApi,
+    addresses: &[Address],
+    balance: Amount,
+) -> bitcoincore_rpc::Result<(Txid, Amount)> {
+    let share = balance / addresses.len() as u64;
+    let amounts: Map<String, Value> = addresses
+        .iter()
+        .map(|address| (address.to_string(), json!(share.to_btc())))
+        .collect();
+    let subtract_fee_from: Vec<String> = addresses.iter().map(Address::to_string).collect();
+    let mut args = vec!["".into(), Value::Object(amounts)];
+    set_arg(&mut args, 4, json!(subtract_fee_from));
+    debug!(
+        "sendmany {} BTC to each of {} addresses",
+        share.to_btc(),
+        addresses.len()
+    );
+    let txid = wallet.call("sendmany", &args)?;
+    debug!("broadcast transaction {txid}");
+    Ok((txid, share))
+}
+
+/// Pick up the payment `saved` by an earlier run, if it was interrupted
+/// while the payment still waited in the mempool, instead of paying twice;
+/// otherwise `send` a new one. Returns the txid, and the PSBT stages when it
+/// was sent afresh through the PSBT workflow.
+pub fn resume_or_send(
+    wallet: &impl RpcApi,
+    saved: Option<&Txid>,
+    send: impl FnOnce() -> Result<(Txid, Option<PsbtStages>), TxError>,
+) -> Result<(Txid, Option<PsbtStages>), TxError> {
+    if let Some(txid) = saved {
+        if is_pending(wallet, txid)? {
+            info!("Resuming payment {txid}, still waiting in the mempool");
+            return Ok((*txid, None));
+        }
+        debug!("saved payment {txid} isn't pending, sending a new one");
+    }
+    send()
+}
+
+/// Whether `wallet` has `txid` unconfirmed and the node still holds it in
+/// its mempool.
+fn is_pending(wallet: &impl RpcApi, txid: &Txid) -> Result<bool, TxError> {
+    match tx::wallet_transaction(wallet, txid) {
+        Ok(tx) if tx.info.confirmations == 0 => {}
+        Ok(_) | Err(TxError::NotInWallet(_)) => return Ok(false),
+        Err(err) => return Err(err),
+    }
+    match tx::check_mempool(wallet, txid) {
+        Ok(_) => Ok(true),
+        Err(TxError::NotInMempool(_)) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Run `send` until it succeeds, retrying up to `attempts` times in all
+/// while it fails with a transient node error (still warming up, mempool
+/// full). The pause between tries starts at `delay` and doubles each time.
+///
+/// Anything else, insufficient funds included, is returned straight away, as
+/// is the last error once the attempts run out. Transport errors aren't
+/// retried either: the transaction may have gone out before the connection
+/// dropped.
+pub fn send_with_retry<T>(
+    attempts: u32,
+    delay: Duration,
+    mut send: impl FnMut() -> Result<T, TxError>,
+) -> Result<T, TxError> {
+    let mut delay = delay;
+    let mut attempt = 1;
+    loop {
+        match send() {
+            Err(TxError::Rpc(err)) if attempt < attempts && is_transient(&err) => {
+                warn!("send attempt {attempt}/{attempts} failed, retrying: {err}");
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether the node refused `err`'s call for a reason that may pass.
+fn is_transient(err: &bitcoincore_rpc::Error) -> bool {
+    match err {
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(rpc)) => {
+            rpc.code == RPC_IN_WARMUP
+                || (rpc.code == RPC_VERIFY_REJECTED && rpc.message.contains("mempool full"))
+        }
+        _ => false,
+    }
+}
+
+/// The node's smart fee estimate for confirming within `target` blocks.
+///
+/// `None` when the node has none to give, as on a fresh regtest chain that
+/// hasn't seen enough transactions to estimate from.
+pub fn estimate_fee(client: &impl RpcApi, target: u16) -> Option<FeeRate> {
+    match client.estimate_smart_fee(target, None) {
+        // Reported in BTC per 1000 vbytes, a quarter of that per 1000 weight units
+        Ok(estimate) => estimate
+            .fee_rate
+            .map(|per_kvb| FeeRate::from_sat_per_kwu(per_kvb.to_sat() / 4)),
+        Err(err) => {
+            debug!("no fee estimate: {err}");
+            None
+        }
+    }
+}
+
+/// Log the node's fee estimate and check the rate the payment will pay,
+/// `options.fee_rate` or else the estimate, against `max` (sat/vB).
+///
+/// Fails with [`TxError::FeeRateTooHigh`] above `max`; without one, a spike
+/// is only warned about. Without an estimate or explicit rate there's
+/// nothing to check.
+pub fn check_fee_rate(
+    client: &impl RpcApi,
+    options: &SendOptions,
+    max: Option<f64>,
+) -> Result<(), TxError> {
+    let estimate = estimate_fee(client, FEE_ESTIMATE_TARGET).map(sat_per_vb);
+    match estimate {
+        Some(rate) => {
+            info!("Estimated fee rate for {FEE_ESTIMATE_TARGET} blocks: {rate:.2} sat/vB")
+        }
+        None => info!("No fee estimate available, leaving the fee rate to the node"),
+    }
+    let Some(rate) = options.fee_rate.or(estimate) else {
+        return Ok(());
+    };
+    match max {
+        Some(max) if rate > max => Err(TxError::FeeRateTooHigh { rate, max }),
+        None if rate > FEE_SPIKE_WARNING => {
+            warn!("fee rate {rate:.2} sat/vB is unusually high; set --max-fee-rate to cap it");
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn sat_per_vb(rate: FeeRate) -> f64 {
+    rate.to_sat_per_kwu() as f64 * 4.0 / 1000.0
+}
+
+/// Build and fund the payment transaction without signing or broadcasting it.
+///
+/// Returns the funded transaction, with the wallet's inputs and change output
+/// added, and the fee it would pay.
+pub fn preview_payments(
+    wallet: &impl RpcApi,
+    payments: &[(Address, Amount)],
+    options: &SendOptions,
+) -> bitcoincore_rpc::Result<(Transaction, Amount)> {
+    let unfunded = create_unfunded(wallet, payments, options)?;
+    let funded =
+        wallet.fund_raw_transaction(unfunded.as_str(), Some(&fund_options(options)), None)?;
+    debug!(
+        "funded preview transaction pays {} BTC fee",
+        funded.fee.to_btc()
+    );
+    Ok((funded.transaction()?, funded.fee))
+}
+
+/// Fund, sign and broadcast the payment transaction one RPC at a time, for
+/// options `sendtoaddress` and `sendmany` can't express.
+fn send_funded(
+    wallet: &impl RpcApi,
+    payments: &[(Address, Amount)],
+    options: &SendOptions,
+) -> Result<Txid, TxError> {
+    let unfunded = create_unfunded(wallet, payments, options)?;
+    let funded =
+        wallet.fund_raw_transaction(unfunded.as_str(), Some(&fund_options(options)), None)?;
+    debug!("funded transaction pays {} BTC fee", funded.fee.to_btc());
+
+    let signed = wallet.sign_raw_transaction_with_wallet(&funded.hex, None, None)?;
+    if !signed.complete {
+        return Err(TxError::SigningIncomplete);
+    }
+    let txid = wallet.send_raw_transaction(&signed.hex)?;
+    debug!("broadcast transaction {txid}");
+    Ok(txid)
+}
+
+/// Pay `payments` through the PSBT workflow: fund a PSBT, sign it with the
+/// wallet, finalize it and broadcast the extracted transaction.
+///
+/// The finalized transaction is checked to pay every payee before it's sent.
+/// Returns its txid and the PSBT as it stood after funding and signing.
+pub fn send_payments_psbt(
+    wallet: &impl RpcApi,
+    payments: &[(Address, Amount)],
+    options: &SendOptions,
+) -> Result<(Txid, PsbtStages), TxError> {
+    let psbt_options = WalletCreateFundedPsbtOptions {
+        fee_rate: fee_rate_per_kvb(options),
+        replaceable: options.replaceable.then_some(true),
+        add_inputs: (!options.inputs.is_empty()).then_some(false),
+        change_address: options
+            .change_address
+            .as_ref()
+            .map(|address| address.as_unchecked().clone()),
+        ..Default::default()
+    };
+    let funded = wallet.wallet_create_funded_psbt(
+        &inputs(options),
+        &outputs(payments),
+        None,
+        Some(psbt_options),
+        None,
+    )?;
+    debug!("funded PSBT pays {} BTC fee", funded.fee.to_btc());
+
+    let signed = wallet.wallet_process_psbt(&funded.psbt, Some(true), None, None)?;
+    if !signed.complete {
+        return Err(TxError::SigningIncomplete);
+    }
+    let finalized = wallet.finalize_psbt(&signed.psbt, Some(true))?;
+    let hex = match finalized.hex {
+        Some(hex) if finalized.complete => hex,
+        _ => return Err(TxError::SigningIncomplete),
+    };
+    let tx: Transaction = encode::deserialize(&hex).map_err(bitcoincore_rpc::Error::from)?;
+
+    for (address, amount) in payments {
+        tx::verify_payment(&tx, address, *amount)?;
+    }
+    let txid = wallet.send_raw_transaction(&tx)?;
+    debug!("broadcast transaction {txid}");
+    Ok((
+        txid,
+        PsbtStages {
+            funded: funded.psbt,
+            signed: signed.psbt,
+        },
+    ))
+}
+
+/// Check that every coin in `outpoints` is one `wallet` can spend, and
+/// return what they're worth together.
+pub fn selected_utxos_value(
+    wallet: &impl RpcApi,
+    outpoints: &[OutPoint],
+) -> Result<Amount, TxError> {
+    let unspent = wallet.list_unspent(None, None, None, None, None)?;
+    outpoints
+        .iter()
+        .map(|outpoint| {
+            unspent
+                .iter()
+                .find(|u| u.txid == outpoint.txid && u.vout == outpoint.vout && u.spendable)
+                .map(|u| u.amount)
+                .ok_or(TxError::UtxoNotSpendable(*outpoint))
+        })
+        .sum()
+}
+
+/// The coins `wallet` can spend from addresses carrying `label`, with what
+/// each is worth; none if the label isn't in use.
+pub fn label_utxos(wallet: &impl RpcApi, label: &str) -> Result<Vec<(OutPoint, Amount)>, TxError> {
+    let addresses: Vec<Address> = wallet::addresses_by_label(wallet, label)?
+        .into_iter()
+        .map(|address| address.assume_checked())
+        .collect();
+    // An empty address filter would match every coin
+    if addresses.is_empty() {
+        return Ok(Vec::new());
+    }
+    let filter: Vec<&Address> = addresses.iter().collect();
+    let unspent = wallet.list_unspent(None, None, Some(&filter), None, None)?;
+    Ok(unspent
+        .into_iter()
+        .filter(|u| u.spendable)
+        .map(|u| (OutPoint::new(u.txid, u.vout), u.amount))
+        .collect())
+}
+
+/// The inputs picked in `options`, signalling replaceability themselves since
+/// the funding RPCs leave preset inputs alone.
+fn inputs(options: &SendOptions) -> Vec<CreateRawTransactionInput> {
+    let sequence = options
+        .replaceable
+        .then_some(Sequence::ENABLE_RBF_NO_LOCKTIME.to_consensus_u32());
+    options
+        .inputs
+        .iter()
+        .map(|outpoint| CreateRawTransactionInput {
+            txid: outpoint.txid,
+            vout: outpoint.vout,
+            sequence,
+        })
+        .collect()
+}
+
+/// Create the payment transaction, unfunded, with an `OP_RETURN` output
+/// carrying `options.op_return` if there's any. Returns its hex.
+///
+/// Called directly rather than through [`RpcApi::create_raw_transaction`],
+/// which only takes outputs paying an address.
+fn create_unfunded(
+    wallet: &impl RpcApi,
+    payments: &[(Address, Amount)],
+    options: &SendOptions,
+) -> bitcoincore_rpc::Result<String> {
+    let mut outputs: Map<String, Value> = payments
+        .iter()
+        .map(|(address, amount)| (address.to_string(), json!(amount.to_btc())))
+        .collect();
+    if let Some(data) = &options.op_return {
+        outputs.insert("data".to_owned(), data.to_lower_hex_string().into());
+    }
+    let args = [serde_json::to_value(inputs(options))?, outputs.into()];
+    wallet.call("createrawtransaction", &args)
+}
+
+fn outputs(payments: &[(Address, Amount)]) -> HashMap<String, Amount> {
+    payments
+        .iter()
+        .map(|(address, amount)| (address.to_string(), *amount))
+        .collect()
+}
+
+fn fund_options(options: &SendOptions) -> FundRawTransactionOptions {
+    FundRawTransactionOptions {
+        fee_rate: fee_rate_per_kvb(options),
+        replaceable: options.replaceable.then_some(true),
+        add_inputs: (!options.inputs.is_empty()).then_some(false),
+        change_address: options.change_address.clone(),
+        ..Default::default()
+    }
+}
+
+/// The wallet's funding RPCs take their fee rate in BTC/kvB rather than sat/vB.
+fn fee_rate_per_kvb(options: &SendOptions) -> Option<Amount> {
+    options
+        .fee_rate
+        .map(|sat_per_vb| Amount::from_sat((sat_per_vb * 1000.0).round() as u64))
+}
+
+/// Set the positional argument at `index`, passing null for any skipped ones
+/// so the node applies their defaults.
+fn set_arg(args: &mut Vec<Value>, index: usize, value: Value) {
+    if args.len() <= index {
+        args.resize(index + 1, Value::Null);
+    }
+    args[index] = value;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockRpc;
+    use bitcoincore_rpc::bitcoin::consensus::encode::serialize_hex;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::{absolute, transaction, TxOut};
+
+    const TRADER: &str = "bcrt1qpp98hdd7ewl82me4dqv2nm9hxx0duzq9faas3u";
+    const CHANGE: &str = "bcrt1qun4kphxm503fhl0utulkytee32muwtfqufn3kl";
+
+    fn address(s: &str) -> Address {
+        s.parse::<Address<_>>().unwrap().assume_checked()
+    }
+
+    #[test]
+    fn estimate_is_converted_to_a_fee_rate() {
+        let node = MockRpc::new().on("estimatesmartfee", json!({"feerate": 0.0002, "blocks": 6}));
+        let rate = estimate_fee(&node, 6).unwrap();
+        assert_eq!(rate, FeeRate::from_sat_per_vb(20).unwrap());
+        assert_eq!(node.last_args("estimatesmartfee").unwrap(), [json!(6)]);
+
+        let regtest = MockRpc::new().on(
+            "estimatesmartfee",
+            json!({"errors": ["Insufficient data or no feerate found"], "blocks": 0}),
+        );
+        assert_eq!(estimate_fee(&regtest, 6), None);
+        assert_eq!(estimate_fee(&MockRpc::new(), 6), None);
+    }
+
+    #[test]
+    fn fee_rate_above_the_maximum_stops_the_send() {
+        let node = MockRpc::new().on("estimatesmartfee", json!({"feerate": 0.0002, "blocks": 6}));
+        let estimated = SendOptions::default();
+        assert!(check_fee_rate(&node, &estimated, None).is_ok());
+        assert!(check_fee_rate(&node, &estimated, Some(50.0)).is_ok());
+        assert!(matches!(
+            check_fee_rate(&node, &estimated, Some(10.0)),
+            Err(TxError::FeeRateTooHigh { rate, max }) if rate == 20.0 && max == 10.0
+        ));
+
+        // An explicit rate is the one that gets paid
+        let explicit = SendOptions {
+            fee_rate: Some(5.0),
+            ..Default::default()
+        };
+        assert!(check_fee_rate(&node, &explicit, Some(10.0)).is_ok());
+
+        // Nothing to go on without an estimate
+        let regtest = MockRpc::new().on("estimatesmartfee", json!({"errors": [], "blocks": 0}));
+        assert!(check_fee_rate(&regtest, &estimated, Some(1.0)).is_ok());
+    }
+
+    #[test]
+    fn fan_out_splits_the_balance_and_takes_the_fee_from_every_output() {
+        let wallet = MockRpc::new().on("sendmany", json!(Txid::all_zeros()));
+        let addresses = [address(TRADER), address(CHANGE)];
+        let (txid, share) = fan_out(&wallet, &addresses, Amount::from_btc(50.0).unwrap()).unwrap();
+        assert_eq!(txid, Txid::all_zeros());
+        assert_eq!(share, Amount::from_btc(25.0).unwrap());
+        assert_eq!(
+            wallet.last_args("sendmany").unwrap(),
+            [
+                json!(""),
+                json!({TRADER: 25.0, CHANGE: 25.0}),
+                Value::Null,
+                Value::Null,
+                json!([TRADER, CHANGE]),
+            ]
+        );
+    }
+
+    #[test]
+    fn pending_payment_is_resumed_instead_of_sent_again() {
+        let (saved, fresh) = (Txid::from_byte_array([1; 32]), Txid::all_zeros());
+        let unconfirmed = json!({
+            "txid": saved,
+            "confirmations": 0,
+            "time": 0,
+            "timereceived": 0,
+            "bip125-replaceable": "no",
+            "walletconflicts": [],
+            "amount": -20.0,
+            "fee": -0.00000141,
+            "details": [],
+            "hex": "",
+        });
+        let entry = json!({
+            "vsize": 141,
+            "weight": 561,
+            "time": 0,
+            "height": 101,
+            "descendantcount": 1,
+            "descendantsize": 141,
+            "ancestorcount": 1,
+            "ancestorsize": 141,
+            "wtxid": saved,
+            "fees": {
+                "base": 0.00000141,
+                "modified": 0.00000141,
+                "ancestor": 0.00000141,
+                "descendant": 0.00000141,
+            },
+            "depends": [],
+            "spentby": [],
+            "bip125-replaceable": false,
+            "unbroadcast": false,
+        });
+        let send = || Ok((fresh, None));
+
+        let pending = MockRpc::new()
+            .on("gettransaction", unconfirmed.clone())
+            .on("getmempoolentry", entry);
+        let resumed = resume_or_send(&pending, Some(&saved), || panic!("paid twice"));
+        assert_eq!(resumed.unwrap(), (saved, None));
+
+        // Dropped from the mempool, unknown to the wallet or never saved
+        let dropped = MockRpc::new().on("gettransaction", unconfirmed).on_error(
+            "getmempoolentry",
+            -5,
+            "Transaction not in mempool",
+        );
+        assert_eq!(
+            resume_or_send(&dropped, Some(&saved), send).unwrap().0,
+            fresh
+        );
+        let unknown =
+            MockRpc::new().on_error("gettransaction", -5, "Invalid or non-wallet transaction id");
+        assert_eq!(
+            resume_or_send(&unknown, Some(&saved), send).unwrap().0,
+            fresh
+        );
+        assert_eq!(
+            resume_or_send(&MockRpc::new(), None, send).unwrap().0,
+            fresh
+        );
+    }
+
+    #[test]
+    fn only_transient_send_errors_are_retried() {
+        let (trader, amount) = (address(TRADER), Amount::from_sat(2_000_000_000));
+        let txid = Txid::all_zeros();
+        let send = |wallet: &MockRpc| {
+            send_with_retry(3, Duration::from_millis(1), || {
+                Ok(send_to_address(
+                    wallet,
+                    &trader,
+                    amount,
+                    &SendOptions::default(),
+                )?)
+            })
+        };
+
+        let busy = MockRpc::new()
+            .on_error("sendtoaddress", -28, "Loading wallet...")
+            .on_error("sendtoaddress", -26, "mempool full")
+            .on("sendtoaddress", json!(txid));
+        assert_eq!(send(&busy).unwrap(), txid);
+        assert_eq!(busy.calls("sendtoaddress"), 3);
+
+        let broke = MockRpc::new().on_error("sendtoaddress", -6, "Insufficient funds");
+        assert!(send(&broke).is_err());
+        assert_eq!(broke.calls("sendtoaddress"), 1);
+
+        // Other rejections of the same code aren't transient
+        let low_fee = MockRpc::new().on_error("sendtoaddress", -26, "min relay fee not met");
+        assert!(send(&low_fee).is_err());
+        assert_eq!(low_fee.calls("sendtoaddress"), 1);
+
+        let full = MockRpc::new().on_error("sendtoaddress", -26, "mempool full");
+        assert!(send(&full).is_err());
+        assert_eq!(full.calls("sendtoaddress"), 3);
+    }
+
+    fn psbt_wallet(finalized: &[(&str, u64)]) -> MockRpc {
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: Vec::new(),
+            output: finalized
+                .iter()
+                .map(|(addr, sats)| TxOut {
+                    value: Amount::from_sat(*sats),
+                    script_pubkey: address(addr).script_pubkey(),
+                })
+                .collect(),
+        };
+        MockRpc::new()
+            .on(
+                "walletcreatefundedpsbt",
+                json!({"psbt": "cHNidP8BAfunded", "fee": 0.00000141, "changepos": 0}),
+            )
+            .on(
+                "walletprocesspsbt",
+                json!({"psbt": "cHNidP8BAsigned", "complete": true}),
+            )
+            .on(
+                "finalizepsbt",
+                json!({"hex": serialize_hex(&tx), "complete": true}),
+            )
+            .on("sendrawtransaction", json!(tx.txid()))
+    }
+
+    #[test]
+    fn psbt_send_broadcasts_finalized_payment() {
+        let wallet = psbt_wallet(&[(CHANGE, 2_999_999_859), (TRADER, 2_000_000_000)]);
+        let payments = [(address(TRADER), Amount::from_sat(2_000_000_000))];
+
+        let (_, stages) = send_payments_psbt(&wallet, &payments, &SendOptions::default()).unwrap();
+
+        assert_eq!(stages.funded, "cHNidP8BAfunded");
+        assert_eq!(stages.signed, "cHNidP8BAsigned");
+        assert_eq!(wallet.calls("sendrawtransaction"), 1);
+    }
+
+    #[test]
+    fn change_address_is_passed_to_funding() {
+        let hex = serialize_hex(&Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: Vec::new(),
+            output: Vec::new(),
+        });
+        let wallet = MockRpc::new()
+            .on("createrawtransaction", json!(hex))
+            .on(
+                "fundrawtransaction",
+                json!({"hex": hex, "fee": 0.00000141, "changepos": 0}),
+            )
+            .on(
+                "signrawtransactionwithwallet",
+                json!({"hex": hex, "complete": true}),
+            )
+            .on("sendrawtransaction", json!(Txid::all_zeros()));
+        let payments = [(address(TRADER), Amount::from_sat(2_000_000_000))];
+        let options = SendOptions {
+            change_address: Some(address(CHANGE)),
+            ..SendOptions::default()
+        };
+
+        send_payments(&wallet, &payments, &options).unwrap();
+
+        assert_eq!(
+            wallet.last_args("fundrawtransaction").unwrap()[1]["changeAddress"],
+            CHANGE
+        );
+        assert_eq!(wallet.calls("sendtoaddress"), 0);
+        assert_eq!(wallet.calls("sendrawtransaction"), 1);
+    }
+
+    #[test]
+    fn comment_is_passed_to_the_wallet() {
+        let wallet = MockRpc::new()
+            .on("sendtoaddress", json!(Txid::all_zeros()))
+            .on("sendmany", json!(Txid::all_zeros()));
+        let options = SendOptions {
+            comment: Some("rent".to_owned()),
+            ..SendOptions::default()
+        };
+        let trader = (address(TRADER), Amount::from_sat(2_000_000_000));
+        let change = (address(CHANGE), Amount::from_sat(100_000_000));
+
+        send_payments(&wallet, std::slice::from_ref(&trader), &options).unwrap();
+        assert_eq!(wallet.last_args("sendtoaddress").unwrap()[2], "rent");
+        send_payments(&wallet, &[trader, change], &options).unwrap();
+        assert_eq!(wallet.last_args("sendmany").unwrap()[3], "rent");
+    }
+
+    #[test]
+    fn op_return_data_is_added_as_a_data_output() {
+        let hex = serialize_hex(&Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: Vec::new(),
+            output: Vec::new(),
+        });
+        let wallet = MockRpc::new()
+            .on("createrawtransaction", json!(hex))
+            .on(
+                "fundrawtransaction",
+                json!({"hex": hex, "fee": 0.00000141, "changepos": 0}),
+            )
+            .on(
+                "signrawtransactionwithwallet",
+                json!({"hex": hex, "complete": true}),
+            )
+            .on("sendrawtransaction", json!(Txid::all_zeros()));
+        let payments = [(address(TRADER), Amount::from_sat(2_000_000_000))];
+        let options = SendOptions {
+            op_return: Some(vec![0xde, 0xad, 0xbe, 0xef]),
+            ..SendOptions::default()
+        };
+
+        send_payments(&wallet, &payments, &options).unwrap();
+
+        let outputs = &wallet.last_args("createrawtransaction").unwrap()[1];
+        assert_eq!(outputs["data"], "deadbeef");
+        assert_eq!(outputs[TRADER], json!(20.0));
+        assert_eq!(wallet.calls("sendtoaddress"), 0);
+    }
+
+    #[test]
+    fn selected_utxos_must_be_spendable() {
+        let unspent = |txid: Txid, vout: u32, spendable: bool| {
+            json!({
+                "txid": txid,
+                "vout": vout,
+                "address": CHANGE,
+                "scriptPubKey": address(CHANGE).script_pubkey().to_hex_string(),
+                "amount": 50.0,
+                "confirmations": 101,
+                "spendable": spendable,
+                "solvable": true,
+                "safe": true,
+            })
+        };
+        let (coin, other) = (Txid::all_zeros(), Txid::from_byte_array([1; 32]));
+        let wallet = MockRpc::new().on(
+            "listunspent",
+            json!([unspent(coin, 0, true), unspent(other, 1, false)]),
+        );
+
+        let value = selected_utxos_value(&wallet, &[OutPoint::new(coin, 0)]).unwrap();
+        assert_eq!(value, Amount::from_sat(5_000_000_000));
+        assert!(matches!(
+            selected_utxos_value(&wallet, &[OutPoint::new(coin, 1)]),
+            Err(TxError::UtxoNotSpendable(_))
+        ));
+        assert!(matches!(
+            selected_utxos_value(&wallet, &[OutPoint::new(other, 1)]),
+            Err(TxError::UtxoNotSpendable(_))
+        ));
+    }
+
+    #[test]
+    fn label_utxos_come_from_the_labeled_addresses_only() {
+        let coin = |txid: Txid, spendable: bool| {
+            json!({
+                "txid": txid,
+                "vout": 0,
+                "address": CHANGE,
+                "label": "Savings",
+                "scriptPubKey": address(CHANGE).script_pubkey().to_hex_string(),
+                "amount": 1.5,
+                "confirmations": 6,
+                "spendable": spendable,
+                "solvable": true,
+                "safe": true,
+            })
+        };
+        let (spendable, watched) = (Txid::all_zeros(), Txid::from_byte_array([1; 32]));
+        let wallet = MockRpc::new()
+            .on(
+                "getaddressesbylabel",
+                json!({ CHANGE: {"purpose": "receive"} }),
+            )
+            .on_error("getaddressesbylabel", -11, "No addresses with label Unused")
+            .on(
+                "listunspent",
+                json!([coin(spendable, true), coin(watched, false)]),
+            );
+
+        let utxos = label_utxos(&wallet, "Savings").unwrap();
+        assert_eq!(
+            utxos,
+            [(OutPoint::new(spendable, 0), Amount::from_sat(150_000_000))]
+        );
+        assert_eq!(wallet.last_args("listunspent").unwrap()[2], json!([CHANGE]));
+
+        // An unused label has no coins, rather than every coin
+        assert!(label_utxos(&wallet, "Unused").unwrap().is_empty());
+        assert_eq!(wallet.calls("listunspent"), 1);
+    }
+
+    #[test]
+    fn psbt_without_trader_output_is_not_broadcast() {
+        let wallet = psbt_wallet(&[(CHANGE, 4_999_999_859)]);
+        let payments = [(address(TRADER), Amount::from_sat(2_000_000_000))];
+
+        assert!(matches!(
+            send_payments_psbt(&wallet, &payments, &SendOptions::default()),
+            Err(TxError::PaymentNotFound(_))
+        ));
+        assert_eq!(wallet.calls("sendrawtransaction"), 0);
+    }
+}
@@ -0,0 +1,116 @@
+//! The `--config` TOML file, holding the settings for one environment.
+//!
+//! Settings are taken from, in order of precedence: command-line flags, the
+//! environment, this file, then the built-in defaults.
+
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Everything a `--config` file may set; all of it optional.
+///
+/// ```toml
+/// network = "regtest"
+/// amount = 20.0
+/// out = "../out.txt"
+///
+/// [wallets]
+/// miner = "Miner"
+/// trader = "Trader"
+///
+/// [rpc]
+/// url = "http://127.0.0.1:18443"
+/// user = "alice"
+/// pass = "password"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub network: Option<String>,
+    /// In BTC.
+    pub amount: Option<f64>,
+    pub out: Option<PathBuf>,
+    pub wallets: WalletSettings,
+    pub rpc: RpcSettings,
+}
+
+/// The `[wallets]` table: names of the wallets to pay from and to.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WalletSettings {
+    pub miner: Option<String>,
+    pub trader: Option<String>,
+}
+
+/// The `[rpc]` table, standing in for the `BITCOIN_RPC_*` variables.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RpcSettings {
+    pub url: Option<String>,
+    pub user: Option<String>,
+    pub pass: Option<String>,
+}
+
+impl Settings {
+    /// Read and parse the file at `path`.
+    pub fn load(path: &Path) -> Result<Settings, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("cannot read config {}: {err}", path.display()))?;
+        toml::from_str(&contents).map_err(|err| format!("invalid config {}: {err}", path.display()))
+    }
+
+    /// The value the file gives for environment variable `key`, if any.
+    pub fn lookup(&self, key: &str) -> Option<String> {
+        match key {
+            "BITCOIN_RPC_URL" => self.rpc.url.clone(),
+            "BITCOIN_RPC_USER" => self.rpc.user.clone(),
+            "BITCOIN_RPC_PASS" => self.rpc.pass.clone(),
+            _ => None,
+        }
+    }
+}
+
+/// Look variables up with `env` first, then in `file`.
+pub fn layered<'a>(
+    env: impl Fn(&str) -> Option<String> + 'a,
+    file: &'a Settings,
+) -> impl Fn(&str) -> Option<String> + 'a {
+    move |key| env(key).or_else(|| file.lookup(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_setting_is_read_from_toml() {
+        let settings: Settings = toml::from_str(
+            r#"
+            network = "signet"
+            amount = 0.5
+            out = "reports/out.txt"
+
+            [wallets]
+            miner = "Alice"
+
+            [rpc]
+            url = "http://node:38332"
+            user = "bob"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(settings.network.as_deref(), Some("signet"));
+        assert_eq!(settings.amount, Some(0.5));
+        assert_eq!(settings.out, Some(PathBuf::from("reports/out.txt")));
+        assert_eq!(settings.wallets.miner.as_deref(), Some("Alice"));
+        assert_eq!(settings.wallets.trader, None);
+        assert_eq!(
+            settings.lookup("BITCOIN_RPC_URL").as_deref(),
+            Some("http://node:38332")
+        );
+        assert_eq!(settings.lookup("BITCOIN_RPC_PASS"), None);
+
+        // A typo is an error rather than silently ignored
+        assert!(toml::from_str::<Settings>("netwrok = \"signet\"").is_err());
+    }
+}
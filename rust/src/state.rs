@@ -0,0 +1,54 @@
+//! The `--once` state file, remembering the last payment a run made.
+
+use crate::report::write_atomic;
+use bitcoincore_rpc::bitcoin::Txid;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The txid recorded in the state file at `path`, or `None` if there's no
+/// file yet (or it's empty).
+pub fn load_state(path: &Path) -> io::Result<Option<Txid>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let recorded = contents.trim();
+    if recorded.is_empty() {
+        return Ok(None);
+    }
+    recorded.parse().map(Some).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} holds no txid ({recorded:?}): {err}", path.display()),
+        )
+    })
+}
+
+/// Record `txid` in the state file at `path`, replacing what was there.
+/// Written atomically, so a crash never leaves a half-written state behind.
+pub fn save_state(path: &Path, txid: &Txid) -> io::Result<()> {
+    write_atomic(path, format!("{txid}\n").as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+
+    #[test]
+    fn saved_txid_loads_back() {
+        let path = std::env::temp_dir().join(format!("state-test-{}", std::process::id()));
+        assert_eq!(load_state(&path).unwrap(), None);
+
+        let txid = Txid::from_byte_array([3; 32]);
+        save_state(&path, &txid).unwrap();
+        assert_eq!(load_state(&path).unwrap(), Some(txid));
+
+        fs::write(&path, "not a txid").unwrap();
+        let err = load_state(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+}
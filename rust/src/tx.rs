@@ -0,0 +1,1414 @@
+use crate::mining::MiningError;
+use crate::rpc;
+use bitcoincore_rpc::bitcoin::amount::serde::as_btc;
+use bitcoincore_rpc::bitcoin::script::Instruction;
+use bitcoincore_rpc::bitcoin::{
+    address, Address, Amount, BlockHash, Network, OutPoint, Script, SignedAmount, Transaction,
+    TxOut, Txid,
+};
+use bitcoincore_rpc::json::{GetRawTransactionResult, GetTransactionResult};
+use bitcoincore_rpc::{jsonrpc, RpcApi};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+
+/// Bitcoin Core's `RPC_INVALID_ADDRESS_OR_KEY`, returned for a txid the
+/// mempool or wallet doesn't hold.
+pub(crate) const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+
+/// Problems found while inspecting the payment transaction.
+#[derive(Debug)]
+pub enum TxError {
+    /// More than one output pays back into the Miner wallet.
+    AmbiguousChange(usize),
+    /// No output pays the `--change-address`.
+    ChangeNotFound(Address),
+    /// An output script doesn't correspond to a standard address.
+    ScriptToAddress(address::Error),
+    /// The transaction conflicts with a confirmed one and can never confirm.
+    Conflicted(Txid),
+    /// The transaction didn't reach the wanted depth before the timeout.
+    ConfirmationTimeout {
+        txid: Txid,
+        confirmations: u32,
+        target: u32,
+    },
+    /// The transaction isn't in a block yet.
+    Unconfirmed(Txid),
+    /// The wallet has no transaction with this id.
+    NotInWallet(Txid),
+    /// Neither the wallet nor the node has a transaction with this id.
+    UnknownTransaction(Txid),
+    /// The outputs are worth more than the inputs they spend.
+    NegativeFee { inputs: Amount, outputs: Amount },
+    /// An input spends an output its previous transaction doesn't have.
+    MissingPrevout(OutPoint),
+    /// No output pays the expected address.
+    PaymentNotFound(Address),
+    /// The output paying the expected address carries the wrong amount.
+    PaymentMismatch {
+        address: Address,
+        expected: Amount,
+        actual: Amount,
+    },
+    /// The wallet holds no unconfirmed output of the transaction to spend.
+    NoChangeToSpend(Txid),
+    /// A child transaction can't pay a fee larger than the output it spends.
+    FeeExceedsChange { change: Amount, fee: Amount },
+    /// The wallet couldn't sign every input of a transaction.
+    SigningIncomplete,
+    /// An address expected to belong to the wallet doesn't.
+    NotMine(Address),
+    /// No output pays into the Trader wallet.
+    TraderNotPaid,
+    /// The transaction has no inputs to take the Miner's address from.
+    NoInputs(Txid),
+    /// A freshly broadcast transaction isn't in the node's mempool.
+    NotInMempool(Txid),
+    /// A coin picked with `--spend-utxo` isn't an unspent output the wallet
+    /// can spend.
+    UtxoNotSpendable(OutPoint),
+    /// The payment would pay a higher fee rate (sat/vB) than allowed.
+    FeeRateTooHigh { rate: f64, max: f64 },
+    /// The raw hex put in the report decodes to a different transaction.
+    RawHexMismatch { expected: Txid, actual: Txid },
+    /// The wallet won't abandon the transaction: it's confirmed or still in
+    /// the mempool.
+    NotAbandonable(Txid),
+    /// The inputs don't add up to the outputs plus the fee.
+    Unbalanced {
+        inputs: Amount,
+        outputs: Vec<Amount>,
+        fee: Amount,
+    },
+    /// Mining the block meant to confirm the transaction failed.
+    Mining(MiningError),
+    /// Querying the wallet about an output failed.
+    Rpc(bitcoincore_rpc::Error),
+}
+
+impl fmt::Display for TxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TxError::AmbiguousChange(count) => {
+                write!(
+                    f,
+                    "expected one change output, found {count} owned by the wallet"
+                )
+            }
+            TxError::ChangeNotFound(address) => {
+                write!(f, "no output pays the change address {address}")
+            }
+            TxError::ScriptToAddress(err) => {
+                write!(f, "failed to convert output script to address: {err}")
+            }
+            TxError::Conflicted(txid) => {
+                write!(
+                    f,
+                    "transaction {txid} conflicts with a confirmed transaction"
+                )
+            }
+            TxError::ConfirmationTimeout {
+                txid,
+                confirmations,
+                target,
+            } => write!(
+                f,
+                "transaction {txid} has {confirmations} of {target} confirmations after timeout"
+            ),
+            TxError::Unconfirmed(txid) => write!(f, "transaction {txid} is not confirmed"),
+            TxError::NotInWallet(txid) => {
+                write!(f, "transaction {txid} is unknown to the Miner wallet")
+            }
+            TxError::UnknownTransaction(txid) => write!(
+                f,
+                "transaction {txid} is unknown to both the Miner wallet and the node"
+            ),
+            TxError::NegativeFee { inputs, outputs } => write!(
+                f,
+                "outputs ({} BTC) exceed inputs ({} BTC)",
+                outputs.to_btc(),
+                inputs.to_btc()
+            ),
+            TxError::MissingPrevout(outpoint) => {
+                write!(f, "input spends unknown output {outpoint}")
+            }
+            TxError::PaymentNotFound(address) => {
+                write!(f, "no output pays {address}")
+            }
+            TxError::PaymentMismatch {
+                address,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "output to {address} pays {} BTC, expected {} BTC",
+                actual.to_btc(),
+                expected.to_btc()
+            ),
+            TxError::NoChangeToSpend(txid) => {
+                write!(f, "transaction {txid} has no unconfirmed change to spend")
+            }
+            TxError::FeeExceedsChange { change, fee } => write!(
+                f,
+                "fee of {} BTC exceeds the {} BTC change output",
+                fee.to_btc(),
+                change.to_btc()
+            ),
+            TxError::SigningIncomplete => write!(f, "wallet could not sign every input"),
+            TxError::FeeRateTooHigh { rate, max } => write!(
+                f,
+                "fee rate {rate:.2} sat/vB is above --max-fee-rate {max} sat/vB, not sending"
+            ),
+            TxError::NotMine(address) => write!(f, "{address} does not belong to the wallet"),
+            TxError::TraderNotPaid => write!(f, "no output pays the Trader wallet"),
+            TxError::NoInputs(txid) => write!(f, "transaction {txid} has no inputs"),
+            TxError::NotInMempool(txid) => {
+                write!(
+                    f,
+                    "transaction {txid} is not in the mempool after broadcast"
+                )
+            }
+            TxError::UtxoNotSpendable(outpoint) => {
+                write!(
+                    f,
+                    "{outpoint} is not an unspent output the wallet can spend"
+                )
+            }
+            TxError::RawHexMismatch { expected, actual } => {
+                write!(f, "raw transaction hex decodes to {actual}, not {expected}")
+            }
+            TxError::NotAbandonable(txid) => write!(
+                f,
+                "transaction {txid} can't be abandoned: it's confirmed or still in the mempool"
+            ),
+            TxError::Unbalanced {
+                inputs,
+                outputs,
+                fee,
+            } => {
+                write!(f, "inputs don't balance: {} BTC", inputs.to_btc())?;
+                for output in outputs {
+                    write!(f, " - {} BTC", output.to_btc())?;
+                }
+                let off = balance_difference(*inputs, outputs, *fee);
+                write!(
+                    f,
+                    " - {} BTC fee = {} BTC, not 0",
+                    fee.to_btc(),
+                    off.to_btc()
+                )
+            }
+            TxError::Mining(err) => err.fmt(f),
+            TxError::Rpc(err) => write!(f, "RPC error while inspecting transaction: {err}"),
+        }
+    }
+}
+
+impl Error for TxError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TxError::AmbiguousChange(_)
+            | TxError::ChangeNotFound(_)
+            | TxError::Conflicted(_)
+            | TxError::ConfirmationTimeout { .. }
+            | TxError::Unconfirmed(_)
+            | TxError::NotInWallet(_)
+            | TxError::UnknownTransaction(_)
+            | TxError::NegativeFee { .. }
+            | TxError::MissingPrevout(_)
+            | TxError::PaymentNotFound(_)
+            | TxError::PaymentMismatch { .. }
+            | TxError::NoChangeToSpend(_)
+            | TxError::FeeExceedsChange { .. }
+            | TxError::SigningIncomplete
+            | TxError::NotMine(_)
+            | TxError::TraderNotPaid
+            | TxError::NoInputs(_)
+            | TxError::NotInMempool(_)
+            | TxError::UtxoNotSpendable(_)
+            | TxError::FeeRateTooHigh { .. }
+            | TxError::RawHexMismatch { .. }
+            | TxError::NotAbandonable(_)
+            | TxError::Unbalanced { .. } => None,
+            TxError::ScriptToAddress(err) => Some(err),
+            TxError::Mining(err) => Some(err),
+            TxError::Rpc(err) => Some(err),
+        }
+    }
+}
+
+impl From<bitcoincore_rpc::Error> for TxError {
+    fn from(err: bitcoincore_rpc::Error) -> Self {
+        TxError::Rpc(err)
+    }
+}
+
+impl From<MiningError> for TxError {
+    fn from(err: MiningError) -> Self {
+        TxError::Mining(err)
+    }
+}
+
+/// Where one input of a transaction took its coins from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum InputInfo {
+    /// Newly minted coins; there is no previous output.
+    Coinbase,
+    /// An earlier transaction's output.
+    Spent {
+        outpoint: OutPoint,
+        /// `None` for scripts without an address form.
+        source_address: Option<String>,
+        #[serde(with = "as_btc")]
+        amount: Amount,
+    },
+}
+
+/// How an unconfirmed transaction sits in the node's mempool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MempoolInfo {
+    pub vsize: u64,
+    /// In-mempool ancestors and descendants, each count including the
+    /// transaction itself.
+    pub ancestor_count: u64,
+    pub descendant_count: u64,
+    #[serde(with = "as_btc")]
+    pub fee: Amount,
+    #[serde(with = "as_btc")]
+    pub ancestor_fees: Amount,
+    #[serde(with = "as_btc")]
+    pub descendant_fees: Amount,
+}
+
+/// Look up `txid` in the node's mempool, failing with
+/// [`TxError::NotInMempool`] if it isn't there (e.g. it was rejected).
+pub fn check_mempool(client: &impl RpcApi, txid: &Txid) -> Result<MempoolInfo, TxError> {
+    let entry = client.get_mempool_entry(txid).map_err(|err| match err {
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(ref rpc))
+            if rpc.code == RPC_INVALID_ADDRESS_OR_KEY =>
+        {
+            TxError::NotInMempool(*txid)
+        }
+        err => TxError::Rpc(err),
+    })?;
+    Ok(MempoolInfo {
+        vsize: entry.vsize,
+        ancestor_count: entry.ancestor_count,
+        descendant_count: entry.descendant_count,
+        fee: entry.fees.base,
+        ancestor_fees: entry.fees.ancestor,
+        descendant_fees: entry.fees.descendant,
+    })
+}
+
+/// Find the single output of `decoded` that pays back into the `miner` wallet.
+///
+/// Outputs paying one of `payees` are skipped; of the rest, ownership is
+/// confirmed with `getaddressinfo` rather than inferred from the payment
+/// address, so transactions with several recipients are handled too.
+/// Returns `None` when the inputs matched the payments and fee exactly and the
+/// wallet added no change.
+///
+/// Each output is classified and looked up on its own thread, so a
+/// transaction with many outputs doesn't wait on them one after another.
+pub fn find_change_output(
+    miner: &(impl RpcApi + Sync),
+    decoded: &Transaction,
+    payees: &[Address],
+    network: Network,
+) -> Result<Option<(TxOut, Address)>, TxError> {
+    let classified = rpc::par_map(&decoded.output, |output| -> Result<_, TxError> {
+        match change_candidate(output, payees, network) {
+            Some(addr) => Ok(is_mine(miner, &addr)?.then(|| (output.clone(), addr))),
+            None => Ok(None),
+        }
+    });
+    let mut owned = Vec::new();
+    for result in classified {
+        owned.extend(result?);
+    }
+
+    match owned.len() {
+        0 => Ok(None),
+        1 => Ok(owned.pop()),
+        count => Err(TxError::AmbiguousChange(count)),
+    }
+}
+
+/// The change among `outputs`: the only one not paying an address in
+/// `non_change`, without asking any wallet.
+///
+/// Returns `None` when no output is left over, or more than one is.
+pub fn select_change(
+    outputs: &[TxOut],
+    non_change: &[Address],
+    network: Network,
+) -> Option<(Address, Amount)> {
+    let mut candidates = change_candidates(outputs, non_change, network);
+    match (candidates.next(), candidates.next()) {
+        (Some((output, addr)), None) => Some((addr, output.value)),
+        _ => None,
+    }
+}
+
+/// Outputs that could be change: those with an address (so no `OP_RETURN`)
+/// that isn't in `non_change`.
+fn change_candidates<'a>(
+    outputs: &'a [TxOut],
+    non_change: &'a [Address],
+    network: Network,
+) -> impl Iterator<Item = (&'a TxOut, Address)> + 'a {
+    outputs.iter().filter_map(move |output| {
+        change_candidate(output, non_change, network).map(|addr| (output, addr))
+    })
+}
+
+/// The address `output` pays, if that could make it change.
+fn change_candidate(output: &TxOut, non_change: &[Address], network: Network) -> Option<Address> {
+    let addr = output_address(output, network).ok().flatten()?;
+    (!non_change.contains(&addr)).then_some(addr)
+}
+
+/// The standard form an output script takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ScriptType {
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+    OpReturn,
+    /// Anything else, such as bare multisig or a future witness version.
+    Unknown,
+}
+
+/// Tell which standard form `script` takes.
+pub fn classify_script(script: &Script) -> ScriptType {
+    if script.is_p2pkh() {
+        ScriptType::P2pkh
+    } else if script.is_p2sh() {
+        ScriptType::P2sh
+    } else if script.is_p2wpkh() {
+        ScriptType::P2wpkh
+    } else if script.is_p2wsh() {
+        ScriptType::P2wsh
+    } else if script.is_p2tr() {
+        ScriptType::P2tr
+    } else if script.is_op_return() {
+        ScriptType::OpReturn
+    } else {
+        ScriptType::Unknown
+    }
+}
+
+/// Whether an output paying `amount` to `script` is dust: worth less than
+/// the fee to spend it at the default dust relay fee of 3 sat/vB, so nodes
+/// won't relay a transaction creating it. The spend is costed the way
+/// Bitcoin Core does: 148 bytes for a legacy input, 67 vbytes for a witness
+/// one, on top of the output's own size. That puts the threshold at 546 sats
+/// for P2PKH and 294 for P2WPKH; an `OP_RETURN` output is never dust.
+pub fn is_dust(amount: Amount, script: &Script) -> bool {
+    amount < script.dust_value()
+}
+
+/// The data embedded in `tx`'s `OP_RETURN` output, if it has one.
+pub fn op_return_data(tx: &Transaction) -> Option<Vec<u8>> {
+    let output = tx
+        .output
+        .iter()
+        .find(|output| output.script_pubkey.is_op_return())?;
+    let mut data = Vec::new();
+    for instruction in output.script_pubkey.instructions().flatten() {
+        if let Instruction::PushBytes(bytes) = instruction {
+            data.extend_from_slice(bytes.as_bytes());
+        }
+    }
+    Some(data)
+}
+
+/// The address `output` pays, of any witness version (P2TR included).
+///
+/// `None` for a provably unspendable script such as an `OP_RETURN` data
+/// carrier, which pays nobody; any other script without an address form is
+/// an error.
+pub fn output_address(output: &TxOut, network: Network) -> Result<Option<Address>, TxError> {
+    if output.script_pubkey.is_provably_unspendable() {
+        return Ok(None);
+    }
+    Address::from_script(&output.script_pubkey, network)
+        .map(Some)
+        .map_err(TxError::ScriptToAddress)
+}
+
+/// Find the output of `decoded` paying into the `trader` wallet, with its
+/// address and value.
+///
+/// Like [`find_change_output`], every output is classified and looked up on
+/// its own thread.
+pub fn find_trader_output(
+    trader: &(impl RpcApi + Sync),
+    decoded: &Transaction,
+    network: Network,
+) -> Result<(Address, Amount), TxError> {
+    let classified = rpc::par_map(&decoded.output, |output| -> Result<_, TxError> {
+        match output_address(output, network)? {
+            Some(addr) => Ok(is_mine(trader, &addr)?.then_some((addr, output.value))),
+            None => Ok(None),
+        }
+    });
+    // The first owned output in transaction order, however the lookups finished
+    for result in classified {
+        if let Some(paid) = result? {
+            return Ok(paid);
+        }
+    }
+    Err(TxError::TraderNotPaid)
+}
+
+/// Address of the output spent by `tx`'s first input.
+pub fn input_address(
+    client: &impl RpcApi,
+    tx: &Transaction,
+    network: Network,
+) -> Result<Address, TxError> {
+    let outpoint = tx
+        .input
+        .first()
+        .ok_or(TxError::NoInputs(tx.txid()))?
+        .previous_output;
+    let spent = prevout(client, outpoint)?;
+    Address::from_script(&spent.script_pubkey, network).map_err(TxError::ScriptToAddress)
+}
+
+/// Follow each input of `tx` to the output it spends.
+pub fn describe_inputs(
+    client: &impl RpcApi,
+    tx: &Transaction,
+    network: Network,
+) -> Result<Vec<InputInfo>, TxError> {
+    tx.input
+        .iter()
+        .map(|input| {
+            if input.previous_output.is_null() {
+                return Ok(InputInfo::Coinbase);
+            }
+            let spent = prevout(client, input.previous_output)?;
+            Ok(InputInfo::Spent {
+                outpoint: input.previous_output,
+                source_address: Address::from_script(&spent.script_pubkey, network)
+                    .ok()
+                    .map(|address| address.to_string()),
+                amount: spent.value,
+            })
+        })
+        .collect()
+}
+
+/// The output `outpoint` points at, looked up in its transaction.
+fn prevout(client: &impl RpcApi, outpoint: OutPoint) -> Result<TxOut, TxError> {
+    let prev = client.get_raw_transaction(&outpoint.txid, None)?;
+    prev.output
+        .get(outpoint.vout as usize)
+        .cloned()
+        .ok_or(TxError::MissingPrevout(outpoint))
+}
+
+/// Whether `addr` belongs to `wallet`, according to `getaddressinfo`.
+fn is_mine(wallet: &impl RpcApi, addr: &Address) -> Result<bool, TxError> {
+    Ok(wallet.get_address_info(addr)?.is_mine == Some(true))
+}
+
+/// Fail with [`TxError::NotMine`] unless `addr` belongs to `wallet`.
+pub fn assert_is_mine(wallet: &impl RpcApi, addr: &Address) -> Result<(), TxError> {
+    if !is_mine(wallet, addr)? {
+        return Err(TxError::NotMine(addr.clone()));
+    }
+    Ok(())
+}
+
+/// Sum the values of the outputs spent by `tx`'s inputs.
+pub fn total_input_amount(client: &impl RpcApi, tx: &Transaction) -> Result<Amount, TxError> {
+    let mut total = Amount::ZERO;
+    for input in &tx.input {
+        total += prevout(client, input.previous_output)?.value;
+    }
+    Ok(total)
+}
+
+/// Fee paid by the wallet transaction `tx`.
+///
+/// Uses the fee the wallet reports when there is one, and otherwise works it
+/// out as the spent inputs minus the created outputs.
+pub fn transaction_fee(client: &impl RpcApi, tx: &GetTransactionResult) -> Result<Amount, TxError> {
+    // The wallet reports the fee of outgoing transactions as a negative amount
+    if let Some(fee) = tx.fee {
+        return Ok(Amount::from_sat(fee.to_sat().unsigned_abs()));
+    }
+
+    let decoded = tx.transaction().map_err(bitcoincore_rpc::Error::from)?;
+    let inputs = total_input_amount(client, &decoded)?;
+    let outputs: Amount = decoded.output.iter().map(|output| output.value).sum();
+    inputs
+        .checked_sub(outputs)
+        .ok_or(TxError::NegativeFee { inputs, outputs })
+}
+
+/// Height and hash of the block that confirmed `tx`.
+///
+/// Read from the transaction's own `blockhash` rather than the chain tip,
+/// which moves past it once more blocks are mined on top.
+pub fn confirming_block(
+    client: &impl RpcApi,
+    tx: &GetTransactionResult,
+) -> Result<(u64, BlockHash), TxError> {
+    let hash = tx
+        .info
+        .blockhash
+        .ok_or(TxError::Unconfirmed(tx.info.txid))?;
+    let height = client.get_block_header_info(&hash)?.height;
+    Ok((height as u64, hash))
+}
+
+/// What the block that confirmed the payment looked like, with
+/// `--block-details`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BlockDetails {
+    pub tx_count: usize,
+    pub size: usize,
+    pub weight: usize,
+    /// Median time of the block and the ten before it, in Unix seconds.
+    pub median_time: Option<u64>,
+    pub difficulty: f64,
+}
+
+/// Read the details of block `hash` from the node.
+pub fn block_details(client: &impl RpcApi, hash: &BlockHash) -> Result<BlockDetails, TxError> {
+    let info = client.get_block_info(hash)?;
+    Ok(BlockDetails {
+        tx_count: info.n_tx,
+        size: info.size,
+        weight: info.weight,
+        median_time: info.mediantime.map(|time| time as u64),
+        difficulty: info.difficulty,
+    })
+}
+
+/// Whether the wallet behind `client` knows `txid` and has seen it confirm.
+/// A transaction it has never heard of, or has since dropped, isn't.
+pub fn is_confirmed(client: &impl RpcApi, txid: &Txid) -> Result<bool, TxError> {
+    match client.get_transaction(txid, None) {
+        Ok(tx) => Ok(tx.info.confirmations > 0),
+        Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(ref rpc)))
+            if rpc.code == RPC_INVALID_ADDRESS_OR_KEY =>
+        {
+            Ok(false)
+        }
+        Err(err) => Err(TxError::Rpc(err)),
+    }
+}
+
+/// The wallet's record of `txid`, failing with [`TxError::NotInWallet`] if
+/// it has none.
+pub fn wallet_transaction(
+    client: &impl RpcApi,
+    txid: &Txid,
+) -> Result<GetTransactionResult, TxError> {
+    client
+        .get_transaction(txid, None)
+        .map_err(|err| not_in_wallet(err, txid))
+}
+
+/// The comment the wallet keeps with `txid`, read from `gettransaction`
+/// (which [`RpcApi::get_transaction`] doesn't expose). `None` if the wallet
+/// didn't keep one.
+pub fn wallet_comment(client: &impl RpcApi, txid: &Txid) -> Result<Option<String>, TxError> {
+    let tx: serde_json::Value = client
+        .call("gettransaction", &[txid.to_string().into()])
+        .map_err(|err| not_in_wallet(err, txid))?;
+    Ok(tx
+        .get("comment")
+        .and_then(|comment| comment.as_str())
+        .map(str::to_owned))
+}
+
+/// [`TxError::NotInWallet`] if `err` is the node saying it has no `txid`,
+/// else `err` as it is.
+pub fn not_in_wallet(err: bitcoincore_rpc::Error, txid: &Txid) -> TxError {
+    match err {
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(ref rpc))
+            if rpc.code == RPC_INVALID_ADDRESS_OR_KEY =>
+        {
+            TxError::NotInWallet(*txid)
+        }
+        err => TxError::Rpc(err),
+    }
+}
+
+/// The node's record of `txid`, wallet or not, failing with
+/// [`TxError::UnknownTransaction`] if it has none. Without `-txindex` the
+/// node only finds a confirmed transaction given the `block_hash` holding it.
+pub fn chain_transaction(
+    node: &impl RpcApi,
+    txid: &Txid,
+    block_hash: Option<&BlockHash>,
+) -> Result<GetRawTransactionResult, TxError> {
+    node.get_raw_transaction_info(txid, block_hash)
+        .map_err(|err| match not_in_wallet(err, txid) {
+            TxError::NotInWallet(txid) => TxError::UnknownTransaction(txid),
+            err => err,
+        })
+}
+
+/// How big a transaction is, with and without the segwit discount.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxMetrics {
+    /// Weight units: four per byte of base data, one per byte of witness.
+    pub weight: u64,
+    /// Virtual size in vbytes, the weight divided by four and rounded up.
+    pub vsize: usize,
+    /// Serialized size in bytes, witness included.
+    pub size: usize,
+}
+
+/// Measure `tx` as serialized.
+pub fn tx_metrics(tx: &Transaction) -> TxMetrics {
+    TxMetrics {
+        weight: tx.weight().to_wu(),
+        vsize: tx.vsize(),
+        size: tx.total_size(),
+    }
+}
+
+/// Fee rate in sat/vB of a transaction of `vsize` virtual bytes paying `fee`.
+pub fn fee_rate(fee: Amount, vsize: usize) -> f64 {
+    fee.to_sat() as f64 / vsize as f64
+}
+
+/// The first output of `tx` paying `address`, if any.
+pub fn output_to<'a>(tx: &'a Transaction, address: &Address) -> Option<&'a TxOut> {
+    tx.output
+        .iter()
+        .find(|output| address.matches_script_pubkey(&output.script_pubkey))
+}
+
+/// What all outputs of `tx` paying `address` add up to.
+pub fn total_to_address(tx: &Transaction, address: &Address) -> Amount {
+    tx.output
+        .iter()
+        .filter(|output| address.matches_script_pubkey(&output.script_pubkey))
+        .map(|output| output.value)
+        .sum()
+}
+
+/// Check that the outputs of `tx` paying `trader` add up to exactly `amount`.
+pub fn verify_payment(tx: &Transaction, trader: &Address, amount: Amount) -> Result<(), TxError> {
+    if output_to(tx, trader).is_none() {
+        return Err(TxError::PaymentNotFound(trader.clone()));
+    }
+    let actual = total_to_address(tx, trader);
+    if actual != amount {
+        return Err(TxError::PaymentMismatch {
+            address: trader.clone(),
+            expected: amount,
+            actual,
+        });
+    }
+    Ok(())
+}
+
+/// Check that `inputs` minus every one of `outputs` minus `fee` comes to
+/// exactly zero, to the satoshi.
+pub fn verify_balance_equation(
+    inputs: Amount,
+    outputs: &[Amount],
+    fee: Amount,
+) -> Result<(), TxError> {
+    if balance_difference(inputs, outputs, fee) == SignedAmount::ZERO {
+        return Ok(());
+    }
+    Err(TxError::Unbalanced {
+        inputs,
+        outputs: outputs.to_vec(),
+        fee,
+    })
+}
+
+/// What's left of `inputs` once `outputs` and `fee` are taken out; signed, as
+/// a bad report may take out more than went in.
+fn balance_difference(inputs: Amount, outputs: &[Amount], fee: Amount) -> SignedAmount {
+    let spent: i64 = outputs
+        .iter()
+        .chain([&fee])
+        .map(|amount| amount.to_sat() as i64)
+        .sum();
+    SignedAmount::from_sat(inputs.to_sat() as i64 - spent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockRpc;
+    use bitcoincore_rpc::bitcoin::consensus::encode::serialize_hex;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::{absolute, transaction, ScriptBuf, Sequence, TxIn, Witness};
+    use bitcoincore_rpc::bitcoin::{WitnessProgram, WitnessVersion};
+    use serde_json::json;
+
+    fn transaction(inputs: &[OutPoint], outputs: &[u64]) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: inputs
+                .iter()
+                .map(|outpoint| TxIn {
+                    previous_output: *outpoint,
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: outputs
+                .iter()
+                .map(|sats| TxOut {
+                    value: Amount::from_sat(*sats),
+                    script_pubkey: ScriptBuf::new(),
+                })
+                .collect(),
+        }
+    }
+
+    fn wallet_tx(tx: &Transaction, fee: Option<f64>) -> GetTransactionResult {
+        serde_json::from_value(json!({
+            "txid": tx.txid(),
+            "confirmations": 1,
+            "time": 0,
+            "timereceived": 0,
+            "bip125-replaceable": "no",
+            "walletconflicts": [],
+            "amount": -20.0,
+            "fee": fee,
+            "details": [],
+            "hex": serialize_hex(tx),
+        }))
+        .unwrap()
+    }
+
+    fn address(s: &str) -> Address {
+        s.parse::<Address<_>>().unwrap().assume_checked()
+    }
+
+    fn paying(outputs: &[(&Address, u64)]) -> Transaction {
+        let mut tx = transaction(&[], &[]);
+        tx.output = outputs
+            .iter()
+            .map(|(addr, sats)| TxOut {
+                value: Amount::from_sat(*sats),
+                script_pubkey: addr.script_pubkey(),
+            })
+            .collect();
+        tx
+    }
+
+    const TRADER: &str = "bcrt1qpp98hdd7ewl82me4dqv2nm9hxx0duzq9faas3u";
+    const CHANGE: &str = "bcrt1qun4kphxm503fhl0utulkytee32muwtfqufn3kl";
+
+    #[test]
+    fn buried_payment_reports_its_inclusion_block() {
+        // Five blocks were mined, the payment went into the first of them
+        let inclusion = BlockHash::from_byte_array([1; 32]);
+        let mut tx = wallet_tx(&transaction(&[], &[]), None);
+        tx.info.blockhash = Some(inclusion);
+        tx.info.confirmations = 5;
+        let node = MockRpc::new().on(
+            "getblockheader",
+            json!({
+                "hash": inclusion,
+                "confirmations": 5,
+                "height": 102,
+                "version": 536870912,
+                "merkleroot": "00".repeat(32),
+                "time": 0,
+                "mediantime": 0,
+                "nonce": 0,
+                "bits": "207fffff",
+                "difficulty": 4.656542373906925e-10,
+                "chainwork": "00",
+                "nTx": 2,
+            }),
+        );
+
+        assert_eq!(confirming_block(&node, &tx).unwrap(), (102, inclusion));
+        assert_eq!(
+            node.last_args("getblockheader").unwrap()[0],
+            json!(inclusion)
+        );
+
+        tx.info.blockhash = None;
+        assert!(matches!(
+            confirming_block(&node, &tx),
+            Err(TxError::Unconfirmed(_))
+        ));
+    }
+
+    #[test]
+    fn block_details_are_read_from_the_block() {
+        let hash = BlockHash::from_byte_array([1; 32]);
+        let node = MockRpc::new().on(
+            "getblock",
+            json!({
+                "hash": hash,
+                "confirmations": 1,
+                "size": 553,
+                "strippedsize": 372,
+                "weight": 1669,
+                "height": 102,
+                "version": 536870912,
+                "versionHex": "20000000",
+                "merkleroot": "00".repeat(32),
+                "tx": ["11".repeat(32), "22".repeat(32)],
+                "time": 1_700_000_600,
+                "mediantime": 1_700_000_300,
+                "nonce": 1,
+                "bits": "207fffff",
+                "difficulty": 4.656542373906925e-10,
+                "chainwork": "00000000000000000000000000000000000000000000000000000000000000ce",
+                "nTx": 2,
+            }),
+        );
+
+        assert_eq!(
+            block_details(&node, &hash).unwrap(),
+            BlockDetails {
+                tx_count: 2,
+                size: 553,
+                weight: 1669,
+                median_time: Some(1_700_000_300),
+                difficulty: 4.656542373906925e-10,
+            }
+        );
+        assert_eq!(node.last_args("getblock").unwrap(), [json!(hash), json!(1)]);
+    }
+
+    #[test]
+    fn comment_is_read_back_when_the_wallet_kept_it() {
+        let txid = Txid::from_byte_array([1; 32]);
+        let kept = MockRpc::new().on("gettransaction", json!({"txid": txid, "comment": "rent"}));
+        assert_eq!(
+            wallet_comment(&kept, &txid).unwrap(),
+            Some("rent".to_owned())
+        );
+        assert_eq!(kept.last_args("gettransaction").unwrap(), [json!(txid)]);
+
+        let dropped = MockRpc::new().on("gettransaction", json!({"txid": txid}));
+        assert_eq!(wallet_comment(&dropped, &txid).unwrap(), None);
+    }
+
+    #[test]
+    fn only_a_wallet_tx_with_confirmations_is_confirmed() {
+        let tx = transaction(&[], &[50]);
+        let confirmed = json!({
+            "txid": tx.txid(),
+            "confirmations": 1,
+            "time": 0,
+            "timereceived": 0,
+            "bip125-replaceable": "no",
+            "walletconflicts": [],
+            "amount": 0.0,
+            "details": [],
+            "hex": serialize_hex(&tx),
+        });
+        let mut pending = confirmed.clone();
+        pending["confirmations"] = json!(0);
+        let wallet = MockRpc::new()
+            .on("gettransaction", confirmed)
+            .on("gettransaction", pending);
+
+        assert!(is_confirmed(&wallet, &tx.txid()).unwrap());
+        assert!(!is_confirmed(&wallet, &tx.txid()).unwrap());
+    }
+
+    #[test]
+    fn payment_with_requested_amount_verifies() {
+        let (trader, change) = (address(TRADER), address(CHANGE));
+        let tx = paying(&[(&change, 2_999_999_859), (&trader, 2_000_000_000)]);
+        assert!(verify_payment(&tx, &trader, Amount::from_sat(2_000_000_000)).is_ok());
+    }
+
+    #[test]
+    fn payment_split_across_outputs_is_summed() {
+        let (trader, change) = (address(TRADER), address(CHANGE));
+        let tx = paying(&[
+            (&trader, 1_500_000_000),
+            (&change, 2_999_999_859),
+            (&trader, 500_000_000),
+        ]);
+        assert_eq!(
+            total_to_address(&tx, &trader),
+            Amount::from_sat(2_000_000_000)
+        );
+        assert!(verify_payment(&tx, &trader, Amount::from_sat(2_000_000_000)).is_ok());
+        assert!(verify_payment(&tx, &trader, Amount::from_sat(1_500_000_000)).is_err());
+    }
+
+    #[test]
+    fn payment_with_wrong_amount_is_rejected() {
+        let trader = address(TRADER);
+        let tx = paying(&[(&trader, 1_999_999_000)]);
+        assert!(matches!(
+            verify_payment(&tx, &trader, Amount::from_sat(2_000_000_000)),
+            Err(TxError::PaymentMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn missing_payment_is_rejected() {
+        let tx = paying(&[(&address(CHANGE), 2_000_000_000)]);
+        assert!(matches!(
+            verify_payment(&tx, &address(TRADER), Amount::from_sat(2_000_000_000)),
+            Err(TxError::PaymentNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn inputs_covering_outputs_and_fee_balance() {
+        let outputs = [
+            Amount::from_sat(2_000_000_000),
+            Amount::from_sat(2_999_990_000),
+        ];
+        let fee = Amount::from_sat(10_000);
+        assert!(verify_balance_equation(Amount::from_sat(5_000_000_000), &outputs, fee).is_ok());
+    }
+
+    #[test]
+    fn a_satoshi_off_is_unbalanced_and_every_term_is_shown() {
+        let outputs = [
+            Amount::from_sat(2_000_000_000),
+            Amount::from_sat(2_999_990_000),
+        ];
+        let fee = Amount::from_sat(10_001);
+        let err =
+            verify_balance_equation(Amount::from_sat(5_000_000_000), &outputs, fee).unwrap_err();
+        assert!(matches!(err, TxError::Unbalanced { .. }));
+        assert_eq!(
+            err.to_string(),
+            "inputs don't balance: 50 BTC - 20 BTC - 29.9999 BTC - 0.00010001 BTC fee \
+             = -0.00000001 BTC, not 0"
+        );
+    }
+
+    #[test]
+    fn transaction_without_change_has_no_change_output() {
+        let trader = address(TRADER);
+        let tx = paying(&[(&trader, 2_000_000_000)]);
+        let rpc = MockRpc::new();
+        let change = find_change_output(&rpc, &tx, &[trader], Network::Regtest).unwrap();
+        assert!(change.is_none());
+        assert_eq!(rpc.calls("getaddressinfo"), 0);
+    }
+
+    fn address_info(addr: &str, is_mine: bool) -> serde_json::Value {
+        json!({
+            "address": addr,
+            "scriptPubKey": "",
+            "ismine": is_mine,
+            "iswatchonly": false,
+            "solvable": true,
+            "isscript": false,
+            "iswitness": true,
+            "ischange": is_mine,
+            "labels": [],
+        })
+    }
+
+    #[test]
+    fn foreign_address_is_not_mine() {
+        let wallet = MockRpc::new().on("getaddressinfo", address_info(CHANGE, false));
+        assert!(matches!(
+            assert_is_mine(&wallet, &address(CHANGE)),
+            Err(TxError::NotMine(_))
+        ));
+    }
+
+    #[test]
+    fn second_recipient_is_not_mistaken_for_change() {
+        let (trader, other, change) = (
+            address(TRADER),
+            address("bcrt1qz467uuwqquf9zs7w9g6ffkwzlg6x6fqprghn22"),
+            address(CHANGE),
+        );
+        let tx = paying(&[
+            (&other, 1_000_000),
+            (&trader, 2_000_000_000),
+            (&change, 5_000),
+        ]);
+        let wallet = MockRpc::new()
+            .on_args(
+                "getaddressinfo",
+                &[json!(other)],
+                address_info(&other.to_string(), false),
+            )
+            .on_args(
+                "getaddressinfo",
+                &[json!(change)],
+                address_info(CHANGE, true),
+            );
+
+        let (output, found) = find_change_output(&wallet, &tx, &[trader], Network::Regtest)
+            .unwrap()
+            .unwrap();
+        assert_eq!(found, change);
+        assert_eq!(output.value, Amount::from_sat(5_000));
+        assert!(assert_is_mine(&wallet, &found).is_ok());
+    }
+
+    /// A regtest P2TR address for the output key `byte` repeated.
+    fn taproot(byte: u8) -> Address {
+        let program = WitnessProgram::new(WitnessVersion::V1, [byte; 32]).unwrap();
+        Address::from_script(&ScriptBuf::new_witness_program(&program), Network::Regtest).unwrap()
+    }
+
+    #[test]
+    fn taproot_script_maps_back_to_its_bech32m_address() {
+        // BIP 86's first receiving address
+        let key = "a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c";
+        let script = ScriptBuf::from_hex(&format!("5120{key}")).unwrap();
+        let output = TxOut {
+            value: Amount::ZERO,
+            script_pubkey: script,
+        };
+        assert_eq!(
+            output_address(&output, Network::Bitcoin)
+                .unwrap()
+                .unwrap()
+                .to_string(),
+            "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr"
+        );
+
+        let regtest = taproot(7);
+        let output = TxOut {
+            value: Amount::ZERO,
+            script_pubkey: regtest.script_pubkey(),
+        };
+        let found = output_address(&output, Network::Regtest).unwrap().unwrap();
+        assert_eq!(found, regtest);
+        assert!(found.to_string().starts_with("bcrt1p"));
+    }
+
+    #[test]
+    fn op_return_output_has_no_address() {
+        let output = TxOut {
+            value: Amount::ZERO,
+            script_pubkey: ScriptBuf::new_op_return([0xde, 0xad]),
+        };
+        assert_eq!(output_address(&output, Network::Regtest).unwrap(), None);
+    }
+
+    #[test]
+    fn scripts_are_classified_by_their_template() {
+        let classify = |hex: &str| classify_script(&ScriptBuf::from_hex(hex).unwrap());
+        let hash20 = "11".repeat(20);
+        let hash32 = "22".repeat(32);
+        assert_eq!(classify(&format!("76a914{hash20}88ac")), ScriptType::P2pkh);
+        assert_eq!(classify(&format!("a914{hash20}87")), ScriptType::P2sh);
+        assert_eq!(classify(&format!("0014{hash20}")), ScriptType::P2wpkh);
+        assert_eq!(classify(&format!("0020{hash32}")), ScriptType::P2wsh);
+        assert_eq!(classify(&format!("5120{hash32}")), ScriptType::P2tr);
+        assert_eq!(classify("6a04deadbeef"), ScriptType::OpReturn);
+        // Bare 1-of-1 multisig, and a witness version nothing uses yet
+        let key = format!("02{hash32}");
+        assert_eq!(classify(&format!("5121{key}51ae")), ScriptType::Unknown);
+        assert_eq!(classify(&format!("5220{hash32}")), ScriptType::Unknown);
+        assert_eq!(classify(""), ScriptType::Unknown);
+    }
+
+    #[test]
+    fn dust_threshold_depends_on_the_output_type() {
+        let sats = Amount::from_sat;
+        let p2pkh = ScriptBuf::from_hex(&format!("76a914{}88ac", "11".repeat(20))).unwrap();
+        assert!(is_dust(sats(545), &p2pkh));
+        assert!(!is_dust(sats(546), &p2pkh));
+
+        let p2wpkh = address(CHANGE).script_pubkey();
+        assert!(is_dust(sats(293), &p2wpkh));
+        assert!(!is_dust(sats(294), &p2wpkh));
+        // Still dust for a P2PKH output
+        assert!(is_dust(sats(294), &p2pkh));
+
+        assert!(!is_dust(
+            Amount::ZERO,
+            &ScriptBuf::new_op_return([0xde, 0xad])
+        ));
+    }
+
+    #[test]
+    fn op_return_data_is_read_back() {
+        let (trader, change) = (address(TRADER), address(CHANGE));
+        let mut tx = paying(&[(&trader, 2_000_000_000), (&change, 2_999_999_859)]);
+        assert_eq!(op_return_data(&tx), None);
+
+        tx.output.insert(
+            1,
+            TxOut {
+                value: Amount::ZERO,
+                script_pubkey: ScriptBuf::new_op_return([0xde, 0xad, 0xbe, 0xef]),
+            },
+        );
+        assert_eq!(op_return_data(&tx), Some(vec![0xde, 0xad, 0xbe, 0xef]));
+        // Neither a payment nor change
+        assert_eq!(
+            select_change(&tx.output, std::slice::from_ref(&trader), Network::Regtest),
+            Some((change, Amount::from_sat(2_999_999_859)))
+        );
+    }
+
+    #[test]
+    fn taproot_change_and_payment_are_detected() {
+        let (trader, change) = (taproot(1), taproot(2));
+        let mut tx = paying(&[(&trader, 2_000_000_000), (&change, 2_999_999_859)]);
+        tx.output.push(TxOut {
+            value: Amount::ZERO,
+            script_pubkey: ScriptBuf::new_op_return([0xde, 0xad]),
+        });
+
+        assert!(verify_payment(&tx, &trader, Amount::from_sat(2_000_000_000)).is_ok());
+        assert_eq!(
+            select_change(&tx.output, std::slice::from_ref(&trader), Network::Regtest),
+            Some((change.clone(), Amount::from_sat(2_999_999_859)))
+        );
+        let wallet = MockRpc::new().on("getaddressinfo", address_info(&change.to_string(), true));
+        let (_, found) = find_change_output(&wallet, &tx, &[trader], Network::Regtest)
+            .unwrap()
+            .unwrap();
+        assert_eq!(found, change);
+    }
+
+    #[test]
+    fn single_leftover_output_is_selected_as_change() {
+        let (trader, change) = (address(TRADER), address(CHANGE));
+        let tx = paying(&[(&change, 2_999_999_859), (&trader, 2_000_000_000)]);
+        assert_eq!(
+            select_change(&tx.output, &[trader], Network::Regtest),
+            Some((change, Amount::from_sat(2_999_999_859)))
+        );
+    }
+
+    #[test]
+    fn payment_without_leftover_has_no_change() {
+        let trader = address(TRADER);
+        let tx = paying(&[(&trader, 2_000_000_000)]);
+        assert_eq!(select_change(&tx.output, &[trader], Network::Regtest), None);
+    }
+
+    #[test]
+    fn second_recipient_is_not_selected_as_change() {
+        let (trader, other, change) = (
+            address(TRADER),
+            address("bcrt1qz467uuwqquf9zs7w9g6ffkwzlg6x6fqprghn22"),
+            address(CHANGE),
+        );
+        let tx = paying(&[
+            (&other, 1_000_000),
+            (&trader, 2_000_000_000),
+            (&change, 5_000),
+        ]);
+        assert_eq!(
+            select_change(&tx.output, &[trader.clone(), other], Network::Regtest),
+            Some((change, Amount::from_sat(5_000)))
+        );
+        // Knowing only the Trader, the two leftovers can't be told apart
+        assert_eq!(select_change(&tx.output, &[trader], Network::Regtest), None);
+    }
+
+    #[test]
+    fn trader_output_is_found_by_ownership() {
+        let (trader, change) = (address(TRADER), address(CHANGE));
+        let tx = paying(&[(&change, 2_999_999_859), (&trader, 2_000_000_000)]);
+        let wallet = MockRpc::new()
+            .on_args(
+                "getaddressinfo",
+                &[json!(change)],
+                address_info(CHANGE, false),
+            )
+            .on_args(
+                "getaddressinfo",
+                &[json!(trader)],
+                address_info(TRADER, true),
+            );
+
+        let (found, amount) = find_trader_output(&wallet, &tx, Network::Regtest).unwrap();
+        assert_eq!(found, trader);
+        assert_eq!(amount, Amount::from_sat(2_000_000_000));
+    }
+
+    #[test]
+    fn ownership_of_many_outputs_is_looked_up_concurrently_in_order() {
+        let payees: Vec<Address> = (1..=60).map(taproot).collect();
+        let outputs: Vec<(&Address, u64)> = payees.iter().zip(1_000..).collect();
+        let tx = paying(&outputs);
+        // The Trader owns two of the outputs, the Miner one other
+        let owning = |owned: &[usize]| {
+            payees
+                .iter()
+                .enumerate()
+                .fold(MockRpc::new(), |rpc, (i, addr)| {
+                    let mine = owned.contains(&i);
+                    rpc.on_args(
+                        "getaddressinfo",
+                        &[json!(addr)],
+                        address_info(&addr.to_string(), mine),
+                    )
+                })
+        };
+        let (trader, miner) = (owning(&[41, 17]), owning(&[52]));
+
+        // Whichever lookup finishes first, the earliest output wins
+        let (found, amount) = find_trader_output(&trader, &tx, Network::Regtest).unwrap();
+        assert_eq!(found, payees[17]);
+        assert_eq!(amount, Amount::from_sat(1_017));
+        assert_eq!(trader.calls("getaddressinfo"), payees.len());
+
+        let (output, change) = find_change_output(&miner, &tx, &payees[..10], Network::Regtest)
+            .unwrap()
+            .unwrap();
+        assert_eq!(change, payees[52]);
+        assert_eq!(output.value, Amount::from_sat(1_052));
+        assert_eq!(miner.calls("getaddressinfo"), payees.len() - 10);
+    }
+
+    #[test]
+    fn input_address_comes_from_the_spent_output() {
+        let miner = address("bcrt1qz467uuwqquf9zs7w9g6ffkwzlg6x6fqprghn22");
+        let coinbase = paying(&[(&miner, 5_000_000_000)]);
+        let spend = transaction(&[OutPoint::new(coinbase.txid(), 0)], &[2_000_000_000]);
+        let rpc = MockRpc::new().on("getrawtransaction", json!(serialize_hex(&coinbase)));
+
+        assert_eq!(
+            input_address(&rpc, &spend, Network::Regtest).unwrap(),
+            miner
+        );
+    }
+
+    #[test]
+    fn inputs_are_traced_to_their_sources() {
+        let miner = address("bcrt1qz467uuwqquf9zs7w9g6ffkwzlg6x6fqprghn22");
+        let funding = paying(&[(&miner, 5_000_000_000)]);
+        let spend = transaction(
+            &[OutPoint::null(), OutPoint::new(funding.txid(), 0)],
+            &[2_000_000_000],
+        );
+        let rpc = MockRpc::new().on("getrawtransaction", json!(serialize_hex(&funding)));
+
+        assert_eq!(
+            describe_inputs(&rpc, &spend, Network::Regtest).unwrap(),
+            [
+                InputInfo::Coinbase,
+                InputInfo::Spent {
+                    outpoint: OutPoint::new(funding.txid(), 0),
+                    source_address: Some(miner.to_string()),
+                    amount: Amount::from_sat(5_000_000_000),
+                },
+            ]
+        );
+        assert_eq!(rpc.calls("getrawtransaction"), 1);
+    }
+
+    #[test]
+    fn mempool_entry_is_summarised() {
+        let txid = transaction(&[], &[]).txid();
+        let rpc = MockRpc::new().on(
+            "getmempoolentry",
+            json!({
+                "vsize": 141,
+                "weight": 561,
+                "time": 0,
+                "height": 101,
+                "descendantcount": 1,
+                "descendantsize": 141,
+                "ancestorcount": 1,
+                "ancestorsize": 141,
+                "wtxid": txid,
+                "fees": {
+                    "base": 0.00000141,
+                    "modified": 0.00000141,
+                    "ancestor": 0.00000141,
+                    "descendant": 0.00000141,
+                },
+                "depends": [],
+                "spentby": [],
+                "bip125-replaceable": false,
+                "unbroadcast": true,
+            }),
+        );
+
+        let info = check_mempool(&rpc, &txid).unwrap();
+        assert_eq!(info.ancestor_count, 1);
+        assert_eq!(info.descendant_count, 1);
+        assert_eq!(info.fee, Amount::from_sat(141));
+    }
+
+    #[test]
+    fn segwit_spend_is_discounted() {
+        // One P2WPKH input spent to two P2WPKH outputs
+        let mut tx = paying(&[(&address(TRADER), 2_000_000_000), (&address(CHANGE), 1)]);
+        tx.input.push(TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::from_slice(&[vec![0; 71], vec![2; 33]]),
+        });
+
+        // 113 base bytes count four times, the 109 witness bytes once
+        assert_eq!(
+            tx_metrics(&tx),
+            TxMetrics {
+                weight: 561,
+                vsize: 141,
+                size: 222,
+            }
+        );
+    }
+
+    #[test]
+    fn fee_rate_divides_fee_by_vsize() {
+        assert_eq!(fee_rate(Amount::from_sat(141), 141), 1.0);
+        assert_eq!(fee_rate(Amount::from_sat(705), 141), 5.0);
+        assert_eq!(fee_rate(Amount::from_sat(1_000), 400), 2.5);
+    }
+
+    #[test]
+    fn wallet_reported_fee_is_used_as_absolute_amount() {
+        let tx = transaction(&[], &[]);
+        let rpc = MockRpc::new();
+        let fee = transaction_fee(&rpc, &wallet_tx(&tx, Some(-0.00000141))).unwrap();
+        assert_eq!(fee, Amount::from_sat(141));
+        assert_eq!(rpc.calls("getrawtransaction"), 0);
+    }
+
+    #[test]
+    fn missing_fee_is_computed_from_inputs_and_outputs() {
+        let coinbase = transaction(&[], &[5_000_000_000]);
+        let spend = transaction(
+            &[OutPoint::new(coinbase.txid(), 0)],
+            &[2_000_000_000, 2_999_999_859],
+        );
+        let rpc = MockRpc::new().on("getrawtransaction", json!(serialize_hex(&coinbase)));
+
+        let fee = transaction_fee(&rpc, &wallet_tx(&spend, None)).unwrap();
+        assert_eq!(fee, Amount::from_sat(141));
+    }
+
+    #[test]
+    fn outputs_exceeding_inputs_is_an_error() {
+        let funding = transaction(&[], &[1_000]);
+        let spend = transaction(&[OutPoint::new(funding.txid(), 0)], &[2_000]);
+        let rpc = MockRpc::new().on("getrawtransaction", json!(serialize_hex(&funding)));
+
+        assert!(matches!(
+            transaction_fee(&rpc, &wallet_tx(&spend, None)),
+            Err(TxError::NegativeFee { .. })
+        ));
+    }
+}
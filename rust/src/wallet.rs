@@ -0,0 +1,862 @@
+use crate::rpc::{self, WalletClients};
+use bitcoincore_rpc::bitcoin::address::NetworkUnchecked;
+use bitcoincore_rpc::bitcoin::{Address, Amount, BlockHash, SignedAmount, Txid};
+use bitcoincore_rpc::json::{
+    AddressType, GetTransactionResultDetailCategory, ImportDescriptors, ListUnspentResultEntry,
+    Timestamp,
+};
+use bitcoincore_rpc::{jsonrpc, RpcApi};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+/// Headroom kept on top of the send amount to cover the transaction fee.
+pub const FEE_BUFFER: Amount = Amount::from_sat(100_000);
+
+/// Bitcoin Core's `RPC_WALLET_INVALID_LABEL_NAME`, returned for an unused label.
+const RPC_WALLET_INVALID_LABEL_NAME: i32 = -11;
+
+/// First Bitcoin Core release (v22.0) whose wallet hands out bech32m addresses.
+const MIN_BECH32M_VERSION: usize = 220_000;
+
+/// Downgrade a bech32m request to bech32 when the node at `node_version`
+/// can't produce taproot addresses; other types pass through unchanged.
+pub fn supported_address_type(requested: AddressType, node_version: usize) -> AddressType {
+    if requested == AddressType::Bech32m && node_version < MIN_BECH32M_VERSION {
+        warn!("node version {node_version} doesn't support bech32m addresses, using bech32");
+        return AddressType::Bech32;
+    }
+    requested
+}
+
+/// The wallet can't cover a payment plus the fee buffer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InsufficientFunds {
+    pub balance: Amount,
+    pub needed: Amount,
+}
+
+impl fmt::Display for InsufficientFunds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "insufficient funds: wallet balance is {} BTC but {} BTC is needed (amount plus {} BTC fee buffer)",
+            self.balance.to_btc(),
+            self.needed.to_btc(),
+            FEE_BUFFER.to_btc()
+        )
+    }
+}
+
+impl Error for InsufficientFunds {}
+
+/// Check that `balance` can pay `amount` and still leave room for the fee.
+pub fn check_funds(balance: Amount, amount: Amount) -> Result<(), InsufficientFunds> {
+    let needed = amount + FEE_BUFFER;
+    if balance < needed {
+        return Err(InsufficientFunds { balance, needed });
+    }
+    Ok(())
+}
+
+/// What [`ensure_wallet`] had to do to make a wallet available.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalletSetup {
+    /// The wallet was already loaded.
+    AlreadyLoaded,
+    /// The wallet existed on disk and was loaded.
+    Loaded,
+    /// No such wallet existed, so it was created.
+    Created,
+}
+
+/// Make the wallet `name` available on the node, loading it from the wallet
+/// directory if it exists there and creating it only if it doesn't.
+///
+/// New wallets are descriptor wallets unless `descriptors` is false, for
+/// nodes too old to support them, and are encrypted with `passphrase` if
+/// one is given. Existing wallets are taken as they are.
+pub fn ensure_wallet(
+    rpc: &impl RpcApi,
+    name: &str,
+    descriptors: bool,
+    passphrase: Option<&str>,
+) -> bitcoincore_rpc::Result<WalletSetup> {
+    if rpc.list_wallets()?.iter().any(|loaded| loaded == name) {
+        return Ok(WalletSetup::AlreadyLoaded);
+    }
+    if rpc.list_wallet_dir()?.iter().any(|on_disk| on_disk == name) {
+        rpc.load_wallet(name)?;
+        return Ok(WalletSetup::Loaded);
+    }
+    // Called directly since RpcApi::create_wallet can't pass `descriptors`:
+    // wallet_name, disable_private_keys, blank, passphrase, avoid_reuse,
+    // descriptors
+    let args = [
+        name.into(),
+        Value::Null,
+        Value::Null,
+        passphrase.map_or(Value::Null, Value::from),
+        Value::Null,
+        descriptors.into(),
+    ];
+    rpc.call::<Value>("createwallet", &args)?;
+    Ok(WalletSetup::Created)
+}
+
+/// Make every wallet in `wallets`, given by name and passphrase, available
+/// as [`ensure_wallet`] does: all at once, each on its own thread, or one
+/// after another with `parallel` unset, for nodes that don't take well to
+/// concurrent wallet calls.
+///
+/// Returns what happened to each wallet, in order; one failing doesn't stop
+/// the others, so the caller still learns which ones were created.
+pub fn ensure_wallets<R: RpcApi + Sync>(
+    rpc: &R,
+    wallets: &[(&str, Option<&str>)],
+    descriptors: bool,
+    parallel: bool,
+) -> Vec<Result<WalletSetup, WalletError>> {
+    let setup = |&(name, passphrase): &(&str, Option<&str>)| {
+        ensure_wallet(rpc, name, descriptors, passphrase).map_err(|err| WalletError::Setup {
+            name: name.to_owned(),
+            err,
+        })
+    };
+    if !parallel {
+        return wallets.iter().map(setup).collect();
+    }
+    rpc::par_map(wallets, setup)
+}
+
+/// Bitcoin Core's `RPC_INVALID_ADDRESS_OR_KEY`, returned for a malformed
+/// descriptor or one whose checksum doesn't match.
+const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+/// Bitcoin Core's `RPC_WALLET_PASSPHRASE_INCORRECT`.
+const RPC_WALLET_PASSPHRASE_INCORRECT: i32 = -14;
+/// Bitcoin Core's `RPC_WALLET_WRONG_ENC_STATE`, returned when unlocking a
+/// wallet that isn't encrypted.
+const RPC_WALLET_WRONG_ENC_STATE: i32 = -15;
+
+/// How long an unlocked wallet stays unlocked if it isn't locked again
+/// explicitly. [`Unlocked`] locks it as soon as the send is done, so this
+/// only has to outlast the slowest send: every retry, each up to the RPC
+/// timeout.
+pub const UNLOCK_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Why an encrypted wallet couldn't be used.
+#[derive(Debug)]
+pub enum WalletError {
+    /// The wallet is encrypted but no passphrase was given to unlock it.
+    Locked(String),
+    /// The passphrase doesn't unlock the wallet.
+    WrongPassphrase(String),
+    /// A passphrase was given but the wallet was created without one.
+    NotEncrypted(String),
+    /// The node rejected a descriptor to import, for the given reason.
+    InvalidDescriptor(String),
+    /// The wallet `name` couldn't be loaded or created.
+    Setup {
+        name: String,
+        err: bitcoincore_rpc::Error,
+    },
+    /// The node rejected an RPC call.
+    Rpc(bitcoincore_rpc::Error),
+}
+
+impl fmt::Display for WalletError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalletError::Locked(name) => write!(
+                f,
+                "wallet {name} is encrypted: pass its passphrase with --miner-passphrase"
+            ),
+            WalletError::WrongPassphrase(name) => {
+                write!(f, "the passphrase for wallet {name} is incorrect")
+            }
+            WalletError::NotEncrypted(name) => write!(
+                f,
+                "wallet {name} is not encrypted, so it has no passphrase to unlock it with"
+            ),
+            // The descriptor itself may hold private keys, so it isn't shown
+            WalletError::InvalidDescriptor(reason) => write!(f, "invalid descriptor: {reason}"),
+            WalletError::Setup { name, err } => {
+                write!(f, "could not set up wallet {name}: {err}")
+            }
+            WalletError::Rpc(err) => write!(f, "RPC error: {err}"),
+        }
+    }
+}
+
+impl Error for WalletError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WalletError::Rpc(err) | WalletError::Setup { err, .. } => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<bitcoincore_rpc::Error> for WalletError {
+    fn from(err: bitcoincore_rpc::Error) -> Self {
+        WalletError::Rpc(err)
+    }
+}
+
+/// Fail with [`WalletError::Locked`] if the wallet `name` is encrypted and
+/// locked, so a run without its passphrase stops before trying to sign.
+pub fn check_unlocked(wallet: &impl RpcApi, name: &str) -> Result<(), WalletError> {
+    // Only encrypted wallets report when they lock again; zero means now
+    if wallet.get_wallet_info()?.unlocked_until == Some(0) {
+        return Err(WalletError::Locked(name.to_owned()));
+    }
+    Ok(())
+}
+
+/// An encrypted wallet unlocked by [`unlock`], locked again when dropped.
+pub struct Unlocked<'a, R: RpcApi> {
+    wallet: &'a R,
+    name: String,
+}
+
+impl<R: RpcApi> Drop for Unlocked<'_, R> {
+    fn drop(&mut self) {
+        match self.wallet.call::<Value>("walletlock", &[]) {
+            Ok(_) => debug!("locked wallet {}", self.name),
+            Err(err) => warn!("could not lock wallet {}: {err}", self.name),
+        }
+    }
+}
+
+/// Unlock the encrypted wallet `name` with `passphrase` for at most
+/// [`UNLOCK_TIMEOUT`], until the returned guard is dropped.
+pub fn unlock<'a, R: RpcApi>(
+    wallet: &'a R,
+    name: &str,
+    passphrase: &str,
+) -> Result<Unlocked<'a, R>, WalletError> {
+    let args = [passphrase.into(), UNLOCK_TIMEOUT.as_secs().into()];
+    wallet
+        .call::<Value>("walletpassphrase", &args)
+        .map_err(|err| match err {
+            bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(ref rpc))
+                if rpc.code == RPC_WALLET_PASSPHRASE_INCORRECT =>
+            {
+                WalletError::WrongPassphrase(name.to_owned())
+            }
+            bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(ref rpc))
+                if rpc.code == RPC_WALLET_WRONG_ENC_STATE =>
+            {
+                WalletError::NotEncrypted(name.to_owned())
+            }
+            err => WalletError::Rpc(err),
+        })?;
+    debug!("unlocked wallet {name}");
+    Ok(Unlocked {
+        wallet,
+        name: name.to_owned(),
+    })
+}
+
+/// Every address of `wallet` carrying `label`, sorted; none for a label the
+/// wallet has never used.
+pub fn addresses_by_label(
+    wallet: &impl RpcApi,
+    label: &str,
+) -> bitcoincore_rpc::Result<Vec<Address<NetworkUnchecked>>> {
+    let labeled: BTreeMap<String, Value> = match wallet.call("getaddressesbylabel", &[label.into()])
+    {
+        Ok(labeled) => labeled,
+        Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(err)))
+            if err.code == RPC_WALLET_INVALID_LABEL_NAME =>
+        {
+            BTreeMap::new()
+        }
+        Err(err) => return Err(err),
+    };
+    labeled
+        .keys()
+        .map(|address| {
+            address
+                .parse::<Address<NetworkUnchecked>>()
+                .map_err(|err| bitcoincore_rpc::Error::ReturnedError(err.to_string()))
+        })
+        .collect()
+}
+
+/// Reuse an address of `wallet` already carrying `label`, so repeated runs
+/// don't leak a new one each time; only if there's none is a new address of
+/// `address_type` created under that label.
+pub fn get_or_create_labeled_address(
+    wallet: &impl RpcApi,
+    label: &str,
+    address_type: Option<AddressType>,
+) -> bitcoincore_rpc::Result<Address<NetworkUnchecked>> {
+    // Sorted, so the same address is picked every time
+    if let Some(address) = addresses_by_label(wallet, label)?.into_iter().next() {
+        debug!("reusing {label:?} address {}", address.assume_checked_ref());
+        return Ok(address);
+    }
+    wallet.get_new_address(Some(label), address_type)
+}
+
+/// Import `descriptor` into `wallet` and return the first address it derives,
+/// labeled like any other received-to address.
+///
+/// The descriptor is checked with `getdescriptorinfo` first, which rejects
+/// a wrong checksum; one given without a checksum gets it appended. A wallet
+/// that holds private keys only accepts descriptors that carry them too.
+pub fn import_descriptor_address(
+    wallet: &impl RpcApi,
+    descriptor: &str,
+) -> Result<Address<NetworkUnchecked>, WalletError> {
+    let info = wallet
+        .get_descriptor_info(descriptor)
+        .map_err(|err| match err {
+            bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(ref rpc))
+                if rpc.code == RPC_INVALID_ADDRESS_OR_KEY =>
+            {
+                WalletError::InvalidDescriptor(rpc.message.clone())
+            }
+            err => WalletError::Rpc(err),
+        })?;
+    let descriptor = match &info.checksum {
+        Some(checksum) if !descriptor.contains('#') => format!("{descriptor}#{checksum}"),
+        _ => descriptor.to_owned(),
+    };
+
+    // Only the first address of a ranged descriptor is needed
+    let range = info.is_range.then_some((0, 0));
+    let results = wallet.import_descriptors(ImportDescriptors {
+        descriptor: descriptor.clone(),
+        timestamp: Timestamp::Now,
+        range,
+        label: (!info.is_range).then(|| "Received".to_owned()),
+        ..Default::default()
+    })?;
+    if let Some(failed) = results.into_iter().find(|result| !result.success) {
+        let reason = failed
+            .error
+            .map_or_else(|| "import failed".to_owned(), |err| err.message);
+        return Err(WalletError::InvalidDescriptor(reason));
+    }
+
+    let derived = wallet.derive_addresses(&descriptor, range.map(|_| [0, 0]))?;
+    let address = derived.into_iter().next().ok_or_else(|| {
+        WalletError::InvalidDescriptor("the descriptor derives no address".to_owned())
+    })?;
+    debug!("imported descriptor deriving {address:?}");
+    Ok(address)
+}
+
+#[derive(Deserialize)]
+struct ListDescriptorsResult {
+    descriptors: Vec<DescriptorEntry>,
+}
+
+#[derive(Deserialize)]
+struct DescriptorEntry {
+    desc: String,
+}
+
+/// The output descriptors behind `wallet`'s addresses, from `listdescriptors`.
+pub fn wallet_descriptors(wallet: &impl RpcApi) -> bitcoincore_rpc::Result<Vec<String>> {
+    let result: ListDescriptorsResult = wallet.call("listdescriptors", &[])?;
+    Ok(result
+        .descriptors
+        .into_iter()
+        .map(|entry| entry.desc)
+        .collect())
+}
+
+/// One wallet transaction (or one leg of it, per address touched), as listed
+/// by [`list_since`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxSummary {
+    pub txid: Txid,
+    pub category: GetTransactionResultDetailCategory,
+    /// Negative for sends.
+    pub amount: SignedAmount,
+    pub confirmations: i32,
+}
+
+/// Every transaction `wallet` has seen since `blockhash`, confirmed or not,
+/// from `listsinceblock`.
+pub fn list_since(
+    wallet: &impl RpcApi,
+    blockhash: &BlockHash,
+) -> bitcoincore_rpc::Result<Vec<TxSummary>> {
+    let since = wallet.list_since_block(Some(blockhash), None, None, None)?;
+    Ok(since
+        .transactions
+        .into_iter()
+        .map(|tx| TxSummary {
+            txid: tx.info.txid,
+            category: tx.detail.category,
+            amount: tx.detail.amount,
+            confirmations: tx.info.confirmations,
+        })
+        .collect())
+}
+
+/// Sum the balances of the wallets `names`, loading any `rpc` doesn't have
+/// loaded yet and reaching each through `clients`, and log each one's
+/// balance and the total.
+///
+/// A wallet that can't be loaded or asked for its balance is left out with a
+/// warning rather than failing the whole sum.
+pub fn total_balance_across_wallets<W: RpcApi>(
+    rpc: &impl RpcApi,
+    names: &[&str],
+    clients: &mut WalletClients<W>,
+) -> Amount {
+    let loaded = rpc.list_wallets().unwrap_or_else(|err| {
+        warn!("could not list loaded wallets: {err}");
+        Vec::new()
+    });
+    let mut total = Amount::ZERO;
+    for name in names {
+        let balance = (|| {
+            if !loaded.iter().any(|loaded| loaded == name) {
+                rpc.load_wallet(name)?;
+            }
+            clients.get(name)?.get_balance(None, None)
+        })();
+        match balance {
+            Ok(balance) => {
+                info!("Wallet {name}: {} BTC", balance.to_btc());
+                total += balance;
+            }
+            Err(err) => warn!("skipping wallet {name}: {err}"),
+        }
+    }
+    info!("Total across wallets: {} BTC", total.to_btc());
+    total
+}
+
+/// Print every UTXO of `wallet`, unconfirmed ones included, to stderr as a
+/// table, largest first.
+pub fn dump_utxos(wallet: &impl RpcApi) -> bitcoincore_rpc::Result<()> {
+    let utxos = wallet.list_unspent(Some(0), None, None, None, None)?;
+    eprint!("{}", utxo_table(utxos));
+    Ok(())
+}
+
+/// Render `utxos` as aligned columns of outpoint, amount, confirmations and
+/// address, sorted by amount descending, with their total on the last line.
+fn utxo_table(mut utxos: Vec<ListUnspentResultEntry>) -> String {
+    utxos.sort_by_key(|utxo| std::cmp::Reverse(utxo.amount));
+    let rows: Vec<[String; 4]> = utxos
+        .iter()
+        .map(|utxo| {
+            [
+                format!("{}:{}", utxo.txid, utxo.vout),
+                format!("{:.8}", utxo.amount.to_btc()),
+                utxo.confirmations.to_string(),
+                utxo.address
+                    .as_ref()
+                    .map(|address| address.clone().assume_checked().to_string())
+                    .unwrap_or_default(),
+            ]
+        })
+        .collect();
+    let total: Amount = utxos.iter().map(|utxo| utxo.amount).sum();
+
+    let header = ["OUTPOINT", "AMOUNT", "CONFS", "ADDRESS"].map(str::to_owned);
+    let footer = [
+        "TOTAL".to_owned(),
+        format!("{:.8}", total.to_btc()),
+        String::new(),
+        String::new(),
+    ];
+    let mut widths = [0; 4];
+    for row in [&header, &footer].into_iter().chain(&rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    // Numbers are right-aligned, text left-aligned
+    let mut table = String::new();
+    for row in [&header].into_iter().chain(&rows).chain([&footer]) {
+        let [outpoint, amount, confs, address] = row;
+        let line = format!(
+            "{outpoint:<w0$}  {amount:>w1$}  {confs:>w2$}  {address}",
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+        );
+        table.push_str(line.trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockRpc;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn node(loaded: &[&str], on_disk: &[&str]) -> MockRpc {
+        let dir: Vec<_> = on_disk.iter().map(|name| json!({ "name": name })).collect();
+        MockRpc::new()
+            .on("listwallets", json!(loaded))
+            .on("listwalletdir", json!({ "wallets": dir }))
+            .on("loadwallet", json!({ "name": "Miner", "warning": "" }))
+            .on("createwallet", json!({ "name": "Miner", "warning": "" }))
+    }
+
+    #[test]
+    fn bech32m_needs_a_taproot_capable_node() {
+        assert_eq!(
+            supported_address_type(AddressType::Bech32m, 240_001),
+            AddressType::Bech32m
+        );
+        assert_eq!(
+            supported_address_type(AddressType::Bech32m, 210_100),
+            AddressType::Bech32
+        );
+        assert_eq!(
+            supported_address_type(AddressType::Legacy, 210_100),
+            AddressType::Legacy
+        );
+    }
+
+    #[test]
+    fn loaded_wallet_is_left_alone() {
+        let rpc = node(&["Miner"], &["Miner"]);
+        assert_eq!(
+            ensure_wallet(&rpc, "Miner", true, None).unwrap(),
+            WalletSetup::AlreadyLoaded
+        );
+        assert_eq!(rpc.calls("loadwallet") + rpc.calls("createwallet"), 0);
+    }
+
+    #[test]
+    fn wallet_on_disk_is_loaded() {
+        let rpc = node(&["Trader"], &["Miner", "Trader"]);
+        assert_eq!(
+            ensure_wallet(&rpc, "Miner", true, None).unwrap(),
+            WalletSetup::Loaded
+        );
+        assert_eq!(rpc.calls("loadwallet"), 1);
+        assert_eq!(rpc.calls("createwallet"), 0);
+    }
+
+    #[test]
+    fn missing_wallet_is_created() {
+        let rpc = node(&[], &[]);
+        assert_eq!(
+            ensure_wallet(&rpc, "Miner", true, None).unwrap(),
+            WalletSetup::Created
+        );
+        assert_eq!(rpc.calls("loadwallet"), 0);
+        assert_eq!(rpc.calls("createwallet"), 1);
+        assert_eq!(rpc.last_args("createwallet").unwrap()[5], json!(true));
+    }
+
+    #[test]
+    fn both_wallets_exist_after_parallel_or_sequential_setup() {
+        for parallel in [true, false] {
+            let rpc = node(&[], &["Trader"]);
+            let setups = ensure_wallets(&rpc, &[("Miner", None), ("Trader", None)], true, parallel);
+            let setups: Vec<_> = setups.into_iter().map(Result::unwrap).collect();
+            assert_eq!(setups, [WalletSetup::Created, WalletSetup::Loaded]);
+            assert_eq!(rpc.all_args("createwallet")[0][0], json!("Miner"));
+            assert_eq!(rpc.all_args("loadwallet"), [[json!("Trader")]]);
+        }
+    }
+
+    #[test]
+    fn failed_wallet_setup_names_the_wallet() {
+        let rpc = MockRpc::new()
+            .on("listwallets", json!(["Miner"]))
+            .on("listwalletdir", json!({ "wallets": [] }))
+            .on_error("createwallet", -4, "Wallet file verification failed");
+        let setups = ensure_wallets(&rpc, &[("Miner", None), ("Trader", None)], true, true);
+        assert!(matches!(setups[0], Ok(WalletSetup::AlreadyLoaded)));
+        let err = setups[1].as_ref().unwrap_err();
+        assert!(matches!(err, WalletError::Setup { name, .. } if name == "Trader"));
+        assert!(err
+            .to_string()
+            .starts_with("could not set up wallet Trader: "));
+    }
+
+    #[test]
+    fn new_wallet_is_encrypted_with_the_passphrase() {
+        let rpc = node(&[], &[]);
+        ensure_wallet(&rpc, "Miner", true, Some("hunter2")).unwrap();
+        assert_eq!(rpc.last_args("createwallet").unwrap()[3], json!("hunter2"));
+
+        let rpc = node(&[], &[]);
+        ensure_wallet(&rpc, "Miner", true, None).unwrap();
+        assert_eq!(rpc.last_args("createwallet").unwrap()[3], Value::Null);
+    }
+
+    #[test]
+    fn unlocked_wallet_is_locked_again_when_done() {
+        let wallet = MockRpc::new()
+            .on("walletpassphrase", Value::Null)
+            .on("walletlock", Value::Null);
+        let unlocked = unlock(&wallet, "Miner", "hunter2").unwrap();
+        assert_eq!(
+            wallet.last_args("walletpassphrase").unwrap(),
+            [json!("hunter2"), json!(UNLOCK_TIMEOUT.as_secs())]
+        );
+        assert_eq!(wallet.calls("walletlock"), 0);
+        drop(unlocked);
+        assert_eq!(wallet.calls("walletlock"), 1);
+    }
+
+    #[test]
+    fn unlock_failures_are_told_apart() {
+        let wrong = MockRpc::new().on_error(
+            "walletpassphrase",
+            -14,
+            "Error: The wallet passphrase entered was incorrect.",
+        );
+        assert!(matches!(
+            unlock(&wrong, "Miner", "nope"),
+            Err(WalletError::WrongPassphrase(name)) if name == "Miner"
+        ));
+
+        let plain = MockRpc::new().on_error(
+            "walletpassphrase",
+            -15,
+            "Error: running with an unencrypted wallet, but walletpassphrase was called.",
+        );
+        assert!(matches!(
+            unlock(&plain, "Miner", "hunter2"),
+            Err(WalletError::NotEncrypted(_))
+        ));
+        assert_eq!(plain.calls("walletlock"), 0);
+    }
+
+    const DESCRIPTOR: &str =
+        "wpkh(03a34b99f22c790c4e36b2b3c2c35a36db06226e41c692fc82b8b56ac1c540c5bd)";
+
+    fn descriptor_info() -> Value {
+        json!({
+            "descriptor": format!("{DESCRIPTOR}#8fhd9pwu"),
+            "checksum": "8fhd9pwu",
+            "isrange": false,
+            "issolvable": true,
+            "hasprivatekeys": false,
+        })
+    }
+
+    #[test]
+    fn descriptor_with_a_bad_checksum_is_rejected_before_importing() {
+        let wallet = MockRpc::new().on_error(
+            "getdescriptorinfo",
+            -5,
+            "Provided checksum 'aaaaaaaa' does not match computed checksum '8fhd9pwu'",
+        );
+        let err =
+            import_descriptor_address(&wallet, &format!("{DESCRIPTOR}#aaaaaaaa")).unwrap_err();
+        assert!(matches!(err, WalletError::InvalidDescriptor(_)));
+        assert!(err.to_string().contains("does not match computed checksum"));
+        assert_eq!(wallet.calls("importdescriptors"), 0);
+    }
+
+    #[test]
+    fn imported_descriptor_yields_its_derived_address() {
+        let address = "bcrt1qz467uuwqquf9zs7w9g6ffkwzlg6x6fqprghn22";
+        let wallet = MockRpc::new()
+            .on("getdescriptorinfo", descriptor_info())
+            .on("importdescriptors", json!([{ "success": true }]))
+            .on("deriveaddresses", json!([address]));
+
+        let derived = import_descriptor_address(&wallet, DESCRIPTOR).unwrap();
+
+        assert_eq!(derived.assume_checked().to_string(), address);
+        // The checksum is added before the descriptor goes anywhere else
+        let with_checksum = json!(format!("{DESCRIPTOR}#8fhd9pwu"));
+        assert_eq!(
+            wallet.last_args("importdescriptors").unwrap()[0][0]["desc"],
+            with_checksum
+        );
+        assert_eq!(
+            wallet.last_args("deriveaddresses").unwrap()[0],
+            with_checksum
+        );
+    }
+
+    #[test]
+    fn failed_descriptor_import_reports_the_node_reason() {
+        let wallet = MockRpc::new()
+            .on("getdescriptorinfo", descriptor_info())
+            .on(
+                "importdescriptors",
+                json!([{
+                    "success": false,
+                    "error": {
+                        "code": -4,
+                        "message": "Cannot import descriptor without private keys to a wallet with private keys enabled",
+                    },
+                }]),
+            );
+        let err = import_descriptor_address(&wallet, DESCRIPTOR).unwrap_err();
+        assert!(err.to_string().contains("without private keys"));
+        assert_eq!(wallet.calls("deriveaddresses"), 0);
+    }
+
+    #[test]
+    fn legacy_wallet_can_still_be_created() {
+        let rpc = node(&[], &[]);
+        ensure_wallet(&rpc, "Miner", false, None).unwrap();
+        assert_eq!(rpc.last_args("createwallet").unwrap()[5], json!(false));
+    }
+
+    const MINING: &str = "bcrt1qz467uuwqquf9zs7w9g6ffkwzlg6x6fqprghn22";
+
+    #[test]
+    fn existing_labeled_address_is_reused() {
+        let wallet = MockRpc::new().on(
+            "getaddressesbylabel",
+            json!({ MINING.to_owned(): {"purpose": "receive"} }),
+        );
+        let address = get_or_create_labeled_address(&wallet, "Mining Reward", None).unwrap();
+        assert_eq!(address.assume_checked().to_string(), MINING);
+        assert_eq!(wallet.calls("getnewaddress"), 0);
+    }
+
+    #[test]
+    fn unused_label_gets_a_new_address() {
+        let wallet = MockRpc::new()
+            .on("getaddressesbylabel", json!({}))
+            .on("getnewaddress", json!(MINING));
+        let address = get_or_create_labeled_address(&wallet, "Mining Reward", None).unwrap();
+        assert_eq!(address.assume_checked().to_string(), MINING);
+        assert_eq!(wallet.calls("getnewaddress"), 1);
+    }
+
+    #[test]
+    fn descriptors_are_listed_in_order() {
+        let wallet = MockRpc::new().on(
+            "listdescriptors",
+            json!({
+                "wallet_name": "Miner",
+                "descriptors": [
+                    {"desc": "wpkh([d34db33f/84h/1h/0h]tpubA/0/*)#abcd", "timestamp": 0, "active": true},
+                    {"desc": "tr([d34db33f/86h/1h/0h]tpubB/0/*)#efgh", "timestamp": 0, "active": true},
+                ],
+            }),
+        );
+        let descriptors = wallet_descriptors(&wallet).unwrap();
+        assert_eq!(descriptors.len(), 2);
+        assert!(descriptors[0].starts_with("wpkh("));
+    }
+
+    fn utxo(vout: u32, amount: f64, confirmations: u32) -> ListUnspentResultEntry {
+        serde_json::from_value(json!({
+            "txid": "d39612cc243e8749268d667a76b5062bd10d7d60d612b50117274efe179699c4",
+            "vout": vout,
+            "address": "bcrt1qz467uuwqquf9zs7w9g6ffkwzlg6x6fqprghn22",
+            "scriptPubKey": "",
+            "amount": amount,
+            "confirmations": confirmations,
+            "spendable": true,
+            "solvable": true,
+            "safe": true,
+        }))
+        .unwrap()
+    }
+
+    fn listed(txid: char, category: &str, amount: f64, confirmations: i32) -> Value {
+        json!({
+            "txid": txid.to_string().repeat(64),
+            "category": category,
+            "amount": amount,
+            "vout": 0,
+            "confirmations": confirmations,
+            "time": 0,
+            "timereceived": 0,
+            "bip125-replaceable": "no",
+            "walletconflicts": [],
+        })
+    }
+
+    #[test]
+    fn transactions_since_a_block_are_summarised() {
+        let since = "11".repeat(32);
+        let wallet = MockRpc::new().on(
+            "listsinceblock",
+            json!({
+                "transactions": [listed('a', "send", -20.0, 1), listed('b', "immature", 50.0, 1)],
+                "removed": [],
+                "lastblock": "22".repeat(32),
+            }),
+        );
+
+        let summaries = list_since(&wallet, &since.parse().unwrap()).unwrap();
+        assert_eq!(wallet.last_args("listsinceblock").unwrap()[0], json!(since));
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[0].txid.to_string(), "a".repeat(64));
+        assert_eq!(
+            summaries[0].category,
+            GetTransactionResultDetailCategory::Send
+        );
+        assert_eq!(summaries[0].amount, SignedAmount::from_btc(-20.0).unwrap());
+        assert_eq!(
+            summaries[1].category,
+            GetTransactionResultDetailCategory::Immature
+        );
+        assert_eq!(summaries[1].confirmations, 1);
+    }
+
+    #[test]
+    fn balances_are_summed_skipping_wallets_that_fail_to_load() {
+        let rpc = MockRpc::new()
+            .on("listwallets", json!(["Miner", "Trader"]))
+            .on_error("loadwallet", -18, "Path does not exist");
+        let balances = HashMap::from([("Miner", 29.99999859), ("Trader", 20.0)]);
+        let mut clients = WalletClients::with_connect(move |name| {
+            Ok(MockRpc::new().on("getbalance", json!(balances[name])))
+        });
+
+        let total =
+            total_balance_across_wallets(&rpc, &["Miner", "Trader", "Missing"], &mut clients);
+
+        assert_eq!(total, Amount::from_sat(4_999_999_859));
+        assert_eq!(rpc.last_args("loadwallet").unwrap(), [json!("Missing")]);
+    }
+
+    #[test]
+    fn utxo_table_lists_largest_first_with_total() {
+        let table = utxo_table(vec![utxo(0, 0.5, 3), utxo(1, 50.0, 101)]);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("OUTPOINT"));
+        assert!(lines[1].contains(":1  50.00000000    101  bcrt1q"));
+        assert!(lines[2].contains(":0   0.50000000      3  bcrt1q"));
+        assert!(lines[3].starts_with("TOTAL") && lines[3].ends_with("50.50000000"));
+    }
+
+    const TWENTY_BTC: Amount = Amount::from_sat(2_000_000_000);
+
+    #[test]
+    fn balance_covering_amount_and_buffer_is_accepted() {
+        let balance = Amount::from_sat(5_000_000_000);
+        assert_eq!(check_funds(balance, TWENTY_BTC), Ok(()));
+    }
+
+    #[test]
+    fn balance_without_fee_headroom_is_rejected() {
+        let err = check_funds(TWENTY_BTC, TWENTY_BTC).unwrap_err();
+        assert_eq!(err.balance, TWENTY_BTC);
+        assert_eq!(err.needed, TWENTY_BTC + FEE_BUFFER);
+        assert!(err.to_string().contains("insufficient funds"));
+    }
+
+    #[test]
+    fn empty_wallet_is_rejected() {
+        assert!(check_funds(Amount::ZERO, TWENTY_BTC).is_err());
+    }
+}
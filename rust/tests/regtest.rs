@@ -0,0 +1,80 @@
+//! End-to-end run against a live regtest node.
+//!
+//! Skipped unless `REGTEST_INTEGRATION=1` is set. The node is found through the
+//! usual `BITCOIN_RPC_*` variables, e.g. the one started by docker-compose.
+
+use bitcoincore_rpc::bitcoin::Amount;
+use bitcoincore_rpc::RpcApi;
+use rust::{cli, mining, rpc, Config};
+use std::env;
+use std::sync::Mutex;
+
+/// The tests share one node, and one of them rewinds its chain.
+static NODE: Mutex<()> = Mutex::new(());
+
+fn node_available() -> bool {
+    env::var("REGTEST_INTEGRATION").is_ok_and(|value| value == "1")
+}
+
+#[test]
+fn full_run_reports_confirmed_payment() {
+    if !node_available() {
+        eprintln!("skipping: set REGTEST_INTEGRATION=1 to run against a regtest node");
+        return;
+    }
+    let _node = NODE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    let options = cli::Args {
+        // Separate wallets so the test doesn't disturb the main program's state
+        wallets: cli::WalletNames {
+            miner: "IntegrationMiner".to_owned(),
+            trader: "IntegrationTrader".to_owned(),
+        },
+        ..cli::Args::default()
+    };
+    let config = Config {
+        rpc: rpc::load_config(options.network),
+        options,
+    };
+
+    let report = rust::run(&config).expect("run failed");
+
+    assert_eq!(report.txid.to_string().len(), 64);
+    assert_eq!(report.trader_output_amount, config.options.amount);
+    assert!(report.fee > Amount::ZERO);
+    assert!(report.block_height.expect("report has no block height") > 101);
+    assert!(report.block_hash.is_some());
+}
+
+#[test]
+fn reset_chain_rewinds_to_genesis() {
+    if !node_available() {
+        eprintln!("skipping: set REGTEST_INTEGRATION=1 to run against a regtest node");
+        return;
+    }
+    let _node = NODE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    let options = cli::Args {
+        wallets: cli::WalletNames {
+            miner: "IntegrationMiner".to_owned(),
+            trader: "IntegrationTrader".to_owned(),
+        },
+        reset_chain: true,
+        ..cli::Args::default()
+    };
+    let config = Config {
+        rpc: rpc::load_config(options.network),
+        options,
+    };
+    let node = rpc::connect(&config.rpc, None).expect("connect failed");
+
+    // Funding from a fresh chain mines exactly the blocks now on it
+    let mined = rust::setup(&config).expect("setup failed");
+    assert!(mined > 100);
+    assert_eq!(node.get_block_count().unwrap(), mined);
+
+    assert_eq!(mining::reset_chain(&node).unwrap(), mined);
+    assert_eq!(node.get_block_count().unwrap(), 0);
+    // A second reset finds nothing to rewind
+    assert_eq!(mining::reset_chain(&node).unwrap(), 0);
+}